@@ -40,12 +40,38 @@ impl Convert for ConvertNone {
 impl Convert for ConvertRGB8 {
     fn convert_inplace(&self, data: &mut [u8]) -> ColorType {
         for chunk in data.chunks_exact_mut(4) {
-            //chunk.swap(0, 2);
+            chunk.swap(0, 2);
         }
         ColorType::Rgba8
     }
 }
 
+/// Expand Xbgr2101010 or Abgr2101010 pixels to 16 bit RGBA without losing any precision. The
+/// 10 bit channels are scaled by bit replication so `value >> 6` gives the original back, the
+/// padding of Xbgr2101010 becomes opaque alpha.
+pub fn unpack_2101010(data: &[u8], alpha: bool) -> Vec<u16> {
+    let expand10 = |color: u32| {
+        let color = (color & 0x3ff) as u16;
+        (color << 6) | (color >> 4)
+    };
+
+    let mut pixels = Vec::with_capacity(data.len());
+    for chunk in data.chunks_exact(4) {
+        let pixel = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        pixels.extend_from_slice(&[
+            expand10(pixel),
+            expand10(pixel >> SHIFT10BITS_2),
+            expand10(pixel >> SHIFT10BITS_1),
+            if alpha {
+                (pixel >> 30) as u16 * 0x5555
+            } else {
+                u16::MAX
+            },
+        ]);
+    }
+    pixels
+}
+
 /// Simple conversion from 10 to 8 bits for one channel
 fn convert10_to_8(color: u32) -> u8 {
     ((color >> 2) & 255) as u8
@@ -0,0 +1,141 @@
+use std::{
+    error::Error,
+    fs::{File, OpenOptions},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    sync::Arc,
+    time::Duration,
+};
+
+use nix::{
+    fcntl,
+    sys::memfd,
+    unistd::{sysconf, SysconfVar},
+};
+use wayland_client::protocol::wl_buffer::WlBuffer;
+
+use crate::CaptureRegion;
+
+/// DRM_FORMAT_MOD_LINEAR, the only layout we can allocate without a GPU.
+pub const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+
+/// Linear strides are padded to this many bytes, which is what most GPUs expect when importing
+/// a buffer they did not allocate themselves.
+const STRIDE_ALIGNMENT: u32 = 256;
+
+/// Frame parameters the compositor advertised for a dmabuf copy through the LinuxDmabuf event.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct DmabufFormat {
+    /// DRM fourcc of the buffer.
+    pub format: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Location of one plane inside the dmabuf.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DmabufPlane {
+    pub offset: u32,
+    pub stride: u32,
+}
+
+/// A dmabuf backed wl_buffer the compositor copies frames into.
+#[derive(Debug)]
+pub(crate) struct DmabufBuffer {
+    pub dmabuf_format: DmabufFormat,
+    pub modifier: u64,
+    pub planes: Vec<DmabufPlane>,
+    pub size: usize,
+    pub buffer: WlBuffer,
+    pub fd: OwnedFd,
+}
+
+impl Drop for DmabufBuffer {
+    fn drop(&mut self) {
+        self.buffer.destroy();
+    }
+}
+
+/// A frame copied into a dmabuf, described well enough to import it somewhere else without
+/// touching the pixels.
+///
+/// Like FrameCopy the buffer goes back to the CaptureSession ring once this is dropped, so keep it
+/// alive for as long as the dmabuf is being read.
+#[derive(Debug)]
+pub struct DmabufFrame {
+    /// DRM fourcc of the frame.
+    pub format: u32,
+    pub width: u32,
+    pub height: u32,
+    pub modifier: u64,
+    pub planes: Vec<DmabufPlane>,
+    /// Areas of the frame that changed since the previous capture, in buffer coordinates.
+    pub damage: Vec<CaptureRegion>,
+    /// When the compositor presented the content of the frame, on CLOCK_MONOTONIC.
+    pub presentation_time: Duration,
+    pub(crate) frame_buffer: Arc<DmabufBuffer>,
+}
+
+impl DmabufFrame {
+    /// File descriptor of the dmabuf. Duplicate it if it needs to outlive the frame.
+    pub fn fd(&self) -> BorrowedFd<'_> {
+        self.frame_buffer.fd.as_fd()
+    }
+
+    /// Size of the dmabuf in bytes.
+    pub fn size(&self) -> usize {
+        self.frame_buffer.size
+    }
+}
+
+/// Argument of the UDMABUF_CREATE ioctl, see linux/udmabuf.h.
+#[repr(C)]
+struct UdmabufCreate {
+    memfd: u32,
+    flags: u32,
+    offset: u64,
+    size: u64,
+}
+
+const UDMABUF_FLAGS_CLOEXEC: u32 = 0x01;
+
+nix::ioctl_write_ptr!(udmabuf_create, b'u', 0x42, UdmabufCreate);
+
+/// Allocate a single plane, linear dmabuf for the frame from a memfd through /dev/udmabuf.
+pub(crate) fn allocate_udmabuf(
+    dmabuf_format: DmabufFormat,
+) -> Result<(OwnedFd, Vec<DmabufPlane>, usize), Box<dyn Error>> {
+    // All formats we capture in are 32 bits per pixel.
+    let stride = (dmabuf_format.width * 4).next_multiple_of(STRIDE_ALIGNMENT);
+    let page_size = sysconf(SysconfVar::PAGE_SIZE)?.unwrap_or(4096) as usize;
+    let size = (stride * dmabuf_format.height) as usize;
+    let size = size.next_multiple_of(page_size);
+
+    let device = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/udmabuf")?;
+
+    // udmabuf only accepts memfds that can't shrink underneath it.
+    let mem_fd = memfd::memfd_create(
+        c"wayshot-dmabuf",
+        memfd::MemFdCreateFlag::MFD_CLOEXEC | memfd::MemFdCreateFlag::MFD_ALLOW_SEALING,
+    )?;
+    let mem_file = unsafe { File::from_raw_fd(mem_fd) };
+    mem_file.set_len(size as u64)?;
+    fcntl::fcntl(mem_fd, fcntl::F_ADD_SEALS(fcntl::SealFlag::F_SEAL_SHRINK))?;
+
+    let create = UdmabufCreate {
+        memfd: mem_fd as u32,
+        flags: UDMABUF_FLAGS_CLOEXEC,
+        offset: 0,
+        size: size as u64,
+    };
+    // The dmabuf keeps the memfd pages alive, the memfd itself can be closed right away.
+    let dmabuf_fd = unsafe { udmabuf_create(device.as_raw_fd(), &create)? };
+
+    Ok((
+        unsafe { OwnedFd::from_raw_fd(dmabuf_fd) },
+        vec![DmabufPlane { offset: 0, stride }],
+        size,
+    ))
+}
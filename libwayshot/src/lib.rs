@@ -1,19 +1,20 @@
 mod convert;
+mod dmabuf;
+mod region;
 
 use std::{
     error::Error,
     ffi::CStr,
     fs::File,
-    io::Write,
-    os::unix::prelude::FromRawFd,
-    os::unix::prelude::RawFd,
-    process::exit,
-    sync::atomic::{AtomicBool, Ordering},
-    time::{SystemTime, UNIX_EPOCH},
+    io::{ErrorKind, Write},
+    os::unix::prelude::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use nix::{
     fcntl,
+    poll::{poll, PollFd, PollFlags},
     sys::{memfd, mman, stat},
     unistd,
 };
@@ -30,21 +31,35 @@ use image::{
 use memmap2::MmapMut;
 
 use wayland_client::{
-    delegate_noop,
+    backend::WaylandError,
+    delegate_noop, event_created_child,
     globals::GlobalList,
     protocol::{
         wl_buffer::WlBuffer, wl_output::WlOutput, wl_shm, wl_shm::Format, wl_shm::WlShm,
         wl_shm_pool::WlShmPool,
     },
-    Connection, Dispatch, QueueHandle,
+    Connection, Dispatch, EventQueue, QueueHandle,
     WEnum::Value,
 };
+use wayland_protocols::wp::linux_dmabuf::zv1::client::{
+    zwp_linux_buffer_params_v1::{self, ZwpLinuxBufferParamsV1},
+    zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+};
 use wayland_protocols_wlr::screencopy::v1::client::{
     zwlr_screencopy_frame_v1, zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1,
     zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
 };
 
-use crate::convert::create_converter;
+use crate::{
+    convert::create_converter,
+    dmabuf::{allocate_udmabuf, DmabufBuffer, DmabufFormat, DRM_FORMAT_MOD_LINEAR},
+};
+
+pub use crate::{
+    convert::unpack_2101010,
+    dmabuf::{DmabufFrame, DmabufPlane},
+    region::{intersect_region, Canvas, OutputGeometry, OutputRegion},
+};
 
 /// Type of frame supported by the compositor. For now we only support Argb8888, Xrgb8888, and
 /// Xbgr8888.
@@ -65,13 +80,149 @@ enum FrameState {
     Finished,
 }
 
-/// The copied frame comprising of the FrameFormat, ColorType (Rgba8), and a memory backed shm
-/// file that holds the image data in it.
+/// Number of shm buffers a CaptureSession keeps around for reuse. One buffer per captured output
+/// plus one that is still held downstream is enough for the common cases, the ring grows while
+/// consumers hold on to more frames.
+pub const BUFFER_RING_SIZE: usize = 4;
+
+/// The copied frame comprising of the FrameFormat and a memory backed shm file that holds the
+/// image data in it, as the compositor wrote it in `frame_format.format`.
+///
+/// The shm buffer is owned by the CaptureSession that produced the frame and is handed back to
+/// its ring once the FrameCopy is dropped. Consumers that want to avoid copying the frame can
+/// hold on to the FrameCopy itself, through `AsRef<[u8]>`, or wrap the memfd from `AsFd`.
 #[derive(Debug)]
 pub struct FrameCopy {
     pub frame_format: FrameFormat,
-    pub frame_color_type: ColorType,
-    pub frame_mmap: MmapMut,
+    /// Areas of the frame that changed since the previous capture, in buffer coordinates.
+    pub damage: Vec<CaptureRegion>,
+    /// When the compositor presented the content of the frame, on CLOCK_MONOTONIC.
+    pub presentation_time: Duration,
+    frame_buffer: Arc<ShmBuffer>,
+}
+
+impl FrameCopy {
+    /// Pixel data of the frame, `stride * height` bytes.
+    pub fn data(&self) -> &[u8] {
+        &self.frame_buffer.frame_mmap
+    }
+
+    /// Copy the frame into an image with rows of `dst_stride` bytes, dropping the padding of the
+    /// frame's own rows.
+    pub fn copy_to(&self, dst: &mut [u8], dst_stride: usize) {
+        let frame_format = self.frame_format;
+        copy_rows(
+            self.data(),
+            frame_format.stride as usize,
+            dst,
+            dst_stride,
+            frame_format.width as usize * 4,
+            frame_format.height as usize,
+        );
+    }
+}
+
+impl AsRef<[u8]> for FrameCopy {
+    fn as_ref(&self) -> &[u8] {
+        self.data()
+    }
+}
+
+/// The memfd backing the frame, holding `stride * height` bytes from offset 0. It is reused for
+/// another capture once the FrameCopy is dropped.
+impl AsFd for FrameCopy {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.frame_buffer.mem_file.as_fd()
+    }
+}
+
+/// Copy `height` rows of `row_bytes` bytes between images whose rows are padded to different
+/// strides. The last row of either image may come without padding. Rows are cut to the stride of
+/// either image, and rows past the end of either image are left out.
+pub fn copy_rows(
+    src: &[u8],
+    src_stride: usize,
+    dst: &mut [u8],
+    dst_stride: usize,
+    row_bytes: usize,
+    height: usize,
+) {
+    let row_bytes = row_bytes.min(src_stride).min(dst_stride);
+    if row_bytes == 0 {
+        return;
+    }
+
+    let size = row_bytes * height;
+    if src_stride == row_bytes && dst_stride == row_bytes && size <= src.len() && size <= dst.len()
+    {
+        dst[..size].copy_from_slice(&src[..size]);
+        return;
+    }
+
+    for (src_row, dst_row) in src
+        .chunks(src_stride)
+        .zip(dst.chunks_mut(dst_stride))
+        .take(height)
+    {
+        let len = row_bytes.min(src_row.len()).min(dst_row.len());
+        dst_row[..len].copy_from_slice(&src_row[..len]);
+    }
+}
+
+/// A wl_buffer backed by a memfd of `stride * height` bytes that stays mapped for the lifetime of
+/// the buffer.
+#[derive(Debug)]
+struct ShmBuffer {
+    frame_format: FrameFormat,
+    frame_mmap: MmapMut,
+    buffer: WlBuffer,
+    shm_pool: WlShmPool,
+    mem_file: File,
+}
+
+impl ShmBuffer {
+    fn new(
+        shm: &WlShm,
+        qh: &QueueHandle<CaptureFrameState>,
+        frame_format: FrameFormat,
+    ) -> Result<Self, Box<dyn Error>> {
+        // Bytes of data in the frame = stride * height.
+        let frame_bytes = frame_format.stride * frame_format.height;
+
+        // Create an in memory file and return it's file descriptor.
+        let mem_fd = create_shm_fd()?;
+        let mem_file = unsafe { File::from_raw_fd(mem_fd) };
+        mem_file.set_len(frame_bytes as u64)?;
+
+        let shm_pool = shm.create_pool(mem_fd, frame_bytes as i32, qh, ());
+        let buffer = shm_pool.create_buffer(
+            0,
+            frame_format.width as i32,
+            frame_format.height as i32,
+            frame_format.stride as i32,
+            frame_format.format,
+            qh,
+            (),
+        );
+
+        // Create a writeable memory map backed by a mem_file.
+        let frame_mmap = unsafe { MmapMut::map_mut(&mem_file)? };
+
+        Ok(Self {
+            frame_format,
+            frame_mmap,
+            buffer,
+            shm_pool,
+            mem_file,
+        })
+    }
+}
+
+impl Drop for ShmBuffer {
+    fn drop(&mut self) {
+        self.buffer.destroy();
+        self.shm_pool.destroy();
+    }
 }
 
 /// Struct to store region capture details.
@@ -118,21 +269,52 @@ impl From<EncodingFormat> for &str {
     }
 }
 
-struct CaptureFrameState {
+/// Book-keeping for a screencopy frame that has been requested from the compositor but not yet
+/// handed out as a FrameCopy.
+struct PendingFrame {
+    frame: ZwlrScreencopyFrameV1,
+    output: WlOutput,
+    capture_region: Option<CaptureRegion>,
     formats: Vec<FrameFormat>,
+    /// Whether the frame was requested with copy_with_damage.
+    with_damage: bool,
+    /// Set when the compositor is able to copy the frame into a dmabuf.
+    dmabuf_format: Option<DmabufFormat>,
+    buffer_done: bool,
     state: Option<FrameState>,
-    buffer_done: AtomicBool,
+    damage: Vec<CaptureRegion>,
+    /// Timestamp of the Ready event.
+    presentation_time: Duration,
+    /// Ring buffer the frame is being copied into, set once `copy` has been requested.
+    buffer: Option<Arc<ShmBuffer>>,
+    dmabuf_buffer: Option<Arc<DmabufBuffer>>,
+}
+
+/// Outcome of importing a dmabuf as a wl_buffer.
+enum DmabufImport {
+    Created(WlBuffer),
+    Failed,
+}
+
+struct CaptureFrameState {
+    frames: Vec<PendingFrame>,
+    dmabuf_import: Option<DmabufImport>,
 }
 
 impl Dispatch<ZwlrScreencopyFrameV1, ()> for CaptureFrameState {
     fn event(
-        frame: &mut Self,
-        _: &ZwlrScreencopyFrameV1,
+        state: &mut Self,
+        proxy: &ZwlrScreencopyFrameV1,
         event: zwlr_screencopy_frame_v1::Event,
         _: &(),
         _: &Connection,
         _: &QueueHandle<Self>,
     ) {
+        let Some(frame) = state.frames.iter_mut().find(|frame| &frame.frame == proxy) else {
+            log::debug!("Received event for a discarded frame");
+            return;
+        };
+
         match event {
             zwlr_screencopy_frame_v1::Event::Buffer {
                 format,
@@ -149,32 +331,60 @@ impl Dispatch<ZwlrScreencopyFrameV1, ()> for CaptureFrameState {
                         stride,
                     })
                 } else {
+                    // Skipped, copy_frame fails if none of the formats is usable
                     log::debug!("Received Buffer event with unidentified format");
-                    exit(1);
                 }
             }
             zwlr_screencopy_frame_v1::Event::Flags { .. } => {
                 log::debug!("Received Flags event");
             }
-            zwlr_screencopy_frame_v1::Event::Ready { .. } => {
+            zwlr_screencopy_frame_v1::Event::Ready {
+                tv_sec_hi,
+                tv_sec_lo,
+                tv_nsec,
+            } => {
                 // If the frame is successfully copied, a “flags” and a “ready” events are sent. Otherwise, a “failed” event is sent.
                 // This is useful when we call .copy on the frame object.
                 log::debug!("Received Ready event");
+                let tv_sec = (u64::from(tv_sec_hi) << 32) | u64::from(tv_sec_lo);
+                frame.presentation_time = Duration::new(tv_sec, tv_nsec);
                 frame.state.replace(FrameState::Finished);
             }
             zwlr_screencopy_frame_v1::Event::Failed => {
                 log::debug!("Received Failed event");
                 frame.state.replace(FrameState::Failed);
             }
-            zwlr_screencopy_frame_v1::Event::Damage { .. } => {
+            zwlr_screencopy_frame_v1::Event::Damage {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                // Sent before Ready, possibly several times, when the frame was copied with
+                // copy_with_damage.
                 log::debug!("Received Damage event");
+                frame.damage.push(CaptureRegion {
+                    x_coordinate: x as i32,
+                    y_coordinate: y as i32,
+                    width: width as i32,
+                    height: height as i32,
+                });
             }
-            zwlr_screencopy_frame_v1::Event::LinuxDmabuf { .. } => {
+            zwlr_screencopy_frame_v1::Event::LinuxDmabuf {
+                format,
+                width,
+                height,
+            } => {
                 log::debug!("Received LinuxDmaBuf event");
+                frame.dmabuf_format = Some(DmabufFormat {
+                    format,
+                    width,
+                    height,
+                });
             }
             zwlr_screencopy_frame_v1::Event::BufferDone => {
                 log::debug!("Received bufferdone event");
-                frame.buffer_done.store(true, Ordering::SeqCst);
+                frame.buffer_done = true;
             }
             _ => unreachable!(),
         };
@@ -185,149 +395,521 @@ delegate_noop!(CaptureFrameState: ignore WlShm);
 delegate_noop!(CaptureFrameState: ignore WlShmPool);
 delegate_noop!(CaptureFrameState: ignore WlBuffer);
 delegate_noop!(CaptureFrameState: ignore ZwlrScreencopyManagerV1);
+delegate_noop!(CaptureFrameState: ignore ZwpLinuxDmabufV1);
 
-/// Get a FrameCopy instance with screenshot pixel data for any wl_output object.
-pub fn capture_output_frame(
-    globals: &mut GlobalList,
-    conn: &mut Connection,
-    cursor_overlay: i32,
-    output: WlOutput,
-    capture_region: Option<CaptureRegion>,
-) -> Result<FrameCopy, Box<dyn Error>> {
-    // Connecting to wayland environment.
-    let mut state = CaptureFrameState {
-        formats: Vec::new(),
-        state: None,
-        buffer_done: AtomicBool::new(false),
-    };
-    let mut event_queue = conn.new_event_queue::<CaptureFrameState>();
-    let qh = event_queue.handle();
-
-    // Instantiating screencopy manager.
-    let screencopy_manager = match globals.bind::<ZwlrScreencopyManagerV1, _, _>(&qh, 3..=3, ()) {
-        Ok(x) => x,
-        Err(e) => {
-            log::error!("Failed to create screencopy manager. Does your compositor implement ZwlrScreencopy?");
-            panic!("{:#?}", e);
+impl Dispatch<ZwpLinuxBufferParamsV1, ()> for CaptureFrameState {
+    fn event(
+        state: &mut Self,
+        _: &ZwpLinuxBufferParamsV1,
+        event: zwp_linux_buffer_params_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwp_linux_buffer_params_v1::Event::Created { buffer } => {
+                log::debug!("Received dmabuf Created event");
+                state.dmabuf_import = Some(DmabufImport::Created(buffer));
+            }
+            zwp_linux_buffer_params_v1::Event::Failed => {
+                log::debug!("Received dmabuf Failed event");
+                state.dmabuf_import = Some(DmabufImport::Failed);
+            }
+            _ => unreachable!(),
         }
-    };
+    }
+
+    event_created_child!(CaptureFrameState, ZwpLinuxBufferParamsV1, [
+        zwp_linux_buffer_params_v1::EVT_CREATED_OPCODE => (WlBuffer, ()),
+    ]);
+}
+
+/// A long-lived screencopy session. It binds the screencopy manager and wl_shm once, owns its own
+/// event queue and keeps a ring of shm buffers that are reused across captures, so capturing
+/// frame after frame does not create new file descriptors and mappings every time.
+pub struct CaptureSession {
+    event_queue: EventQueue<CaptureFrameState>,
+    state: CaptureFrameState,
+    screencopy_manager: ZwlrScreencopyManagerV1,
+    shm: WlShm,
+    /// Unset when the compositor or the allocator turned out not to support dmabuf capture.
+    linux_dmabuf: Option<ZwpLinuxDmabufV1>,
+    cursor_overlay: i32,
+    buffers: Vec<Arc<ShmBuffer>>,
+    dmabuf_buffers: Vec<Arc<DmabufBuffer>>,
+}
 
-    // Capture output.
-    let frame: ZwlrScreencopyFrameV1 = if let Some(region) = capture_region {
-        screencopy_manager.capture_output_region(
+impl CaptureSession {
+    /// Create a new capture session on the given connection.
+    pub fn new(
+        globals: &GlobalList,
+        conn: &Connection,
+        cursor_overlay: i32,
+    ) -> Result<Self, Box<dyn Error>> {
+        let event_queue = conn.new_event_queue::<CaptureFrameState>();
+        let qh = event_queue.handle();
+
+        // Instantiating screencopy manager.
+        let screencopy_manager = match globals.bind::<ZwlrScreencopyManagerV1, _, _>(&qh, 3..=3, ())
+        {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("Failed to create screencopy manager. Does your compositor implement ZwlrScreencopy?");
+                return Err(e.into());
+            }
+        };
+
+        // Instantiate shm global.
+        let shm = globals.bind::<WlShm, _, _>(&qh, 1..=1, ())?;
+
+        // Dmabuf capture is optional, we fall back to shm without it.
+        let linux_dmabuf = match globals.bind::<ZwpLinuxDmabufV1, _, _>(&qh, 1..=3, ()) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                log::debug!("zwp_linux_dmabuf_v1 is not available: {}", e);
+                None
+            }
+        };
+
+        Ok(Self {
+            event_queue,
+            state: CaptureFrameState {
+                frames: Vec::new(),
+                dmabuf_import: None,
+            },
+            screencopy_manager,
+            shm,
+            linux_dmabuf,
             cursor_overlay,
-            &output,
-            region.x_coordinate,
-            region.y_coordinate,
-            region.width,
-            region.height,
-            &qh,
-            (),
-        )
-    } else {
-        screencopy_manager.capture_output(cursor_overlay, &output, &qh, ())
-    };
+            buffers: Vec::with_capacity(BUFFER_RING_SIZE),
+            dmabuf_buffers: Vec::with_capacity(BUFFER_RING_SIZE),
+        })
+    }
+
+    /// Get a FrameCopy instance with screenshot pixel data for any wl_output object.
+    ///
+    /// The whole frame is reported as damaged.
+    pub fn capture_output_frame(
+        &mut self,
+        output: &WlOutput,
+        capture_region: Option<CaptureRegion>,
+    ) -> Result<FrameCopy, Box<dyn Error>> {
+        let index = self.request_frame(output, capture_region);
+        self.copy_frame(index, false)?;
 
-    // Empty internal event buffer until buffer_done is set to true which is when the Buffer done
-    // event is fired, aka the capture from the compositor is succesful.
-    while !state.buffer_done.load(Ordering::SeqCst) {
-        event_queue.blocking_dispatch(&mut state)?;
+        while self.state.frames[index].state.is_none() {
+            self.event_queue.blocking_dispatch(&mut self.state)?;
+        }
+
+        let mut frame_copy = self.finish_frame(index)?;
+        frame_copy.damage = vec![CaptureRegion {
+            x_coordinate: 0,
+            y_coordinate: 0,
+            width: frame_copy.frame_format.width as i32,
+            height: frame_copy.frame_format.height as i32,
+        }];
+        Ok(frame_copy)
     }
 
-    log::debug!(
-        "Received compositor frame buffer formats: {:#?}",
-        state.formats
-    );
-    // Filter advertised wl_shm formats and select the first one that matches.
-    let frame_format = state
-        .formats
-        .iter()
-        .find(|frame| {
-            matches!(
-                frame.format,
-                wl_shm::Format::Xbgr2101010
-                    | wl_shm::Format::Abgr2101010
-                    | wl_shm::Format::Argb8888
-                    | wl_shm::Format::Xrgb8888
-                    | wl_shm::Format::Xbgr8888
-            )
+    /// Get a FrameCopy instance once the compositor has new content for the wl_output object,
+    /// using `copy_with_damage`. The areas that changed since the previous capture of this
+    /// session are available in `FrameCopy::damage`.
+    ///
+    /// Returns `None` when nothing changed within `timeout`. The request stays pending and the next
+    /// call for the same output and region picks it up again. Without a timeout this blocks until
+    /// there is damage.
+    pub fn capture_output_frame_with_damage(
+        &mut self,
+        output: &WlOutput,
+        capture_region: Option<CaptureRegion>,
+        timeout: Option<Duration>,
+    ) -> Result<Option<FrameCopy>, Box<dyn Error>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        let index = match self.find_frame(output, capture_region, true) {
+            Some(index) => index,
+            None => {
+                let index = self.request_frame(output, capture_region);
+                self.copy_frame(index, true)?;
+                index
+            }
+        };
+
+        while self.state.frames[index].state.is_none() {
+            match deadline {
+                Some(deadline) => {
+                    if !self.dispatch_until(deadline)? {
+                        return Ok(None);
+                    }
+                }
+                None => {
+                    self.event_queue.blocking_dispatch(&mut self.state)?;
+                }
+            }
+        }
+
+        self.finish_frame(index).map(Some)
+    }
+
+    /// Get a DmabufFrame for any wl_output object, copied by the compositor straight into a
+    /// dmabuf.
+    ///
+    /// Returns `None` when the compositor or the dmabuf allocator can't do this, in which case the
+    /// session stops trying and the caller should fall back to `capture_output_frame`.
+    pub fn capture_output_dmabuf(
+        &mut self,
+        output: &WlOutput,
+        capture_region: Option<CaptureRegion>,
+    ) -> Result<Option<DmabufFrame>, Box<dyn Error>> {
+        let Some(linux_dmabuf) = self.linux_dmabuf.clone() else {
+            return Ok(None);
+        };
+
+        let index = self.request_frame(output, capture_region);
+        while !self.state.frames[index].buffer_done {
+            self.event_queue.blocking_dispatch(&mut self.state)?;
+        }
+
+        let Some(dmabuf_format) = self.state.frames[index].dmabuf_format else {
+            log::warn!("Compositor does not offer dmabuf capture, falling back to shm");
+            self.disable_dmabuf(index);
+            return Ok(None);
+        };
+
+        let buffer = match acquire_from_ring(
+            &mut self.dmabuf_buffers,
+            |buffer| buffer.dmabuf_format == dmabuf_format,
+            || {
+                log::debug!("Allocating dmabuf capture buffer for {:?}", dmabuf_format);
+                import_dmabuf(
+                    &mut self.event_queue,
+                    &mut self.state,
+                    &linux_dmabuf,
+                    dmabuf_format,
+                )
+            },
+        ) {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                log::warn!("Unable to set up a dmabuf, falling back to shm: {}", e);
+                self.disable_dmabuf(index);
+                return Ok(None);
+            }
+        };
+
+        let frame = &mut self.state.frames[index];
+        frame.frame.copy(&buffer.buffer);
+        frame.dmabuf_buffer = Some(buffer);
+
+        while self.state.frames[index].state.is_none() {
+            self.event_queue.blocking_dispatch(&mut self.state)?;
+        }
+
+        let pending = self.state.frames.remove(index);
+        pending.frame.destroy();
+        if pending.state != Some(FrameState::Finished) {
+            log::warn!("Dmabuf frame copy failed, falling back to shm");
+            self.linux_dmabuf = None;
+            self.dmabuf_buffers.clear();
+            return Ok(None);
+        }
+
+        let frame_buffer = pending
+            .dmabuf_buffer
+            .expect("Finished frame without a capture buffer");
+        Ok(Some(DmabufFrame {
+            format: dmabuf_format.format,
+            width: dmabuf_format.width,
+            height: dmabuf_format.height,
+            modifier: frame_buffer.modifier,
+            planes: frame_buffer.planes.clone(),
+            damage: vec![CaptureRegion {
+                x_coordinate: 0,
+                y_coordinate: 0,
+                width: dmabuf_format.width as i32,
+                height: dmabuf_format.height as i32,
+            }],
+            presentation_time: pending.presentation_time,
+            frame_buffer,
+        }))
+    }
+
+    /// Drop a pending frame without copying it and stop using dmabufs for this session.
+    fn disable_dmabuf(&mut self, index: usize) {
+        self.state.frames.remove(index).frame.destroy();
+        self.linux_dmabuf = None;
+        self.dmabuf_buffers.clear();
+    }
+
+    /// Index of the pending frame for the output and region, if there is one.
+    fn find_frame(
+        &self,
+        output: &WlOutput,
+        capture_region: Option<CaptureRegion>,
+        with_damage: bool,
+    ) -> Option<usize> {
+        self.state.frames.iter().position(|frame| {
+            &frame.output == output
+                && frame.capture_region == capture_region
+                && frame.with_damage == with_damage
         })
-        .copied();
-    log::debug!("Selected frame buffer format: {:#?}", frame_format);
+    }
+
+    /// Ask the compositor for a new frame of the output and return its index in the pending list.
+    fn request_frame(&mut self, output: &WlOutput, capture_region: Option<CaptureRegion>) -> usize {
+        let qh = self.event_queue.handle();
+
+        // Capture output.
+        let frame: ZwlrScreencopyFrameV1 = if let Some(region) = capture_region {
+            self.screencopy_manager.capture_output_region(
+                self.cursor_overlay,
+                output,
+                region.x_coordinate,
+                region.y_coordinate,
+                region.width,
+                region.height,
+                &qh,
+                (),
+            )
+        } else {
+            self.screencopy_manager
+                .capture_output(self.cursor_overlay, output, &qh, ())
+        };
+
+        self.state.frames.push(PendingFrame {
+            frame,
+            output: output.clone(),
+            capture_region,
+            formats: Vec::new(),
+            with_damage: false,
+            dmabuf_format: None,
+            buffer_done: false,
+            state: None,
+            damage: Vec::new(),
+            presentation_time: Duration::ZERO,
+            buffer: None,
+            dmabuf_buffer: None,
+        });
+        self.state.frames.len() - 1
+    }
+
+    /// Wait for the buffer parameters of a pending frame, pick a buffer from the ring and ask the
+    /// compositor to copy the frame into it.
+    fn copy_frame(&mut self, index: usize, with_damage: bool) -> Result<(), Box<dyn Error>> {
+        // Empty internal event buffer until buffer_done is set to true which is when the Buffer done
+        // event is fired, aka the capture from the compositor is succesful.
+        while !self.state.frames[index].buffer_done {
+            self.event_queue.blocking_dispatch(&mut self.state)?;
+        }
+
+        let formats = &self.state.frames[index].formats;
+        log::debug!("Received compositor frame buffer formats: {:#?}", formats);
+        // Filter advertised wl_shm formats and select the first one that matches.
+        let frame_format = formats
+            .iter()
+            .find(|frame| {
+                matches!(
+                    frame.format,
+                    wl_shm::Format::Xbgr2101010
+                        | wl_shm::Format::Abgr2101010
+                        | wl_shm::Format::Argb8888
+                        | wl_shm::Format::Xrgb8888
+                        | wl_shm::Format::Xbgr8888
+                        | wl_shm::Format::Abgr8888
+                )
+            })
+            .copied();
+        log::debug!("Selected frame buffer format: {:#?}", frame_format);
 
-    // Check if frame format exists.
-    let frame_format = match frame_format {
-        Some(format) => format,
-        None => {
+        // Check if frame format exists.
+        let Some(frame_format) = frame_format else {
             log::error!("No suitable frame format found");
-            exit(1);
+            return Err("No suitable frame format found".into());
+        };
+
+        let buffer = self.acquire_buffer(frame_format)?;
+
+        // Copy the pixel data advertised by the compositor into the buffer.
+        let frame = &mut self.state.frames[index];
+        if with_damage {
+            frame.frame.copy_with_damage(&buffer.buffer);
+        } else {
+            frame.frame.copy(&buffer.buffer);
         }
-    };
+        frame.with_damage = with_damage;
+        frame.buffer = Some(buffer);
+        Ok(())
+    }
 
-    // Bytes of data in the frame = stride * height.
-    let frame_bytes = frame_format.stride * frame_format.height;
-
-    // Create an in memory file and return it's file descriptor.
-    let mem_fd = create_shm_fd()?;
-    let mem_file = unsafe { File::from_raw_fd(mem_fd) };
-    mem_file.set_len(frame_bytes as u64)?;
-
-    // Instantiate shm global.
-    let shm = globals.bind::<WlShm, _, _>(&qh, 1..=1, ()).unwrap();
-    let shm_pool = shm.create_pool(mem_fd, frame_bytes as i32, &qh, ());
-    let buffer = shm_pool.create_buffer(
-        0,
-        frame_format.width as i32,
-        frame_format.height as i32,
-        frame_format.stride as i32,
-        frame_format.format,
-        &qh,
-        (),
-    );
+    /// Remove a frame the compositor is done with from the pending list and turn it into a
+    /// FrameCopy.
+    fn finish_frame(&mut self, index: usize) -> Result<FrameCopy, Box<dyn Error>> {
+        let pending = self.state.frames.remove(index);
+        pending.frame.destroy();
 
-    // Copy the pixel data advertised by the compositor into the buffer we just created.
-    frame.copy(&buffer);
-    buffer.destroy();
-    shm_pool.destroy();
+        match pending.state {
+            Some(FrameState::Finished) => {}
+            _ => {
+                log::error!("Frame copy failed");
+                return Err("Frame copy failed".into());
+            }
+        }
 
-    // On copy the Ready / Failed events are fired by the frame object, so here we check for them.
-    loop {
-        // Basically reads, if frame state is not None then...
-        if let Some(state) = state.state {
-            match state {
-                FrameState::Failed => {
-                    log::error!("Frame copy failed");
-                    exit(1);
-                }
-                FrameState::Finished => {
-                    // Create a writeable memory map backed by a mem_file.
-                    let mut frame_mmap = unsafe { MmapMut::map_mut(&mem_file)? };
-                    let data = &mut *frame_mmap;
-                    let frame_color_type = if let Some(converter) =
-                        create_converter(frame_format.format)
-                    {
-                        converter.convert_inplace(data)
-                    } else {
-                        log::error!("Unsupported buffer format: {:?}", frame_format.format);
-                        log::error!("You can send a feature request for the above format to the mailing list for wayshot over at https://sr.ht/~shinyzenith/wayshot.");
-                        exit(1);
-                    };
-                    return Ok(FrameCopy {
-                        frame_format,
-                        frame_color_type,
-                        frame_mmap,
-                    });
-                }
+        let buffer = pending
+            .buffer
+            .expect("Finished frame without a capture buffer");
+        Ok(FrameCopy {
+            frame_format: buffer.frame_format,
+            damage: pending.damage,
+            presentation_time: pending.presentation_time,
+            frame_buffer: buffer,
+        })
+    }
+
+    /// Dispatch events, waiting no longer than until `deadline` for them to arrive. Returns false
+    /// if the deadline passed without any event.
+    fn dispatch_until(&mut self, deadline: Instant) -> Result<bool, Box<dyn Error>> {
+        if self.event_queue.dispatch_pending(&mut self.state)? > 0 {
+            return Ok(true);
+        }
+        self.event_queue.flush()?;
+
+        let guard = self.event_queue.prepare_read()?;
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let mut fds = [PollFd::new(
+            guard.connection_fd().as_raw_fd(),
+            PollFlags::POLLIN,
+        )];
+        loop {
+            match poll(&mut fds, timeout.as_millis() as i32) {
+                Ok(0) => return Ok(false),
+                Ok(_) => break,
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(errno) => return Err(errno.into()),
             }
         }
 
-        event_queue.blocking_dispatch(&mut state)?;
+        match guard.read() {
+            Ok(_) => {}
+            Err(WaylandError::Io(e)) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.event_queue.dispatch_pending(&mut self.state)?;
+        Ok(true)
+    }
+
+    /// Return a buffer from the shm ring for the frame format.
+    fn acquire_buffer(
+        &mut self,
+        frame_format: FrameFormat,
+    ) -> Result<Arc<ShmBuffer>, Box<dyn Error>> {
+        let shm = &self.shm;
+        let qh = self.event_queue.handle();
+        acquire_from_ring(
+            &mut self.buffers,
+            |buffer| buffer.frame_format == frame_format,
+            || {
+                log::debug!("Allocating capture buffer for {:?}", frame_format);
+                ShmBuffer::new(shm, &qh, frame_format)
+            },
+        )
+    }
+}
+
+/// Return a buffer from the ring that matches and is not held by any frame or pending copy. A new
+/// buffer is allocated when there is none, replacing an idle buffer that doesn't match once the
+/// ring is full.
+fn acquire_from_ring<T>(
+    ring: &mut Vec<Arc<T>>,
+    matches: impl Fn(&T) -> bool,
+    allocate: impl FnOnce() -> Result<T, Box<dyn Error>>,
+) -> Result<Arc<T>, Box<dyn Error>> {
+    let is_idle = |buffer: &Arc<T>| Arc::strong_count(buffer) == 1;
+
+    if let Some(buffer) = ring
+        .iter()
+        .find(|buffer| matches(buffer) && is_idle(buffer))
+    {
+        return Ok(buffer.clone());
+    }
+
+    let buffer = Arc::new(allocate()?);
+
+    if ring.len() >= BUFFER_RING_SIZE {
+        if let Some(index) = ring.iter().position(is_idle) {
+            ring[index] = buffer.clone();
+            return Ok(buffer);
+        }
+        log::debug!("All capture buffers are in use, growing the ring");
+    }
+    ring.push(buffer.clone());
+    Ok(buffer)
+}
+
+/// Allocate a dmabuf for the frame and have the compositor import it as a wl_buffer.
+fn import_dmabuf(
+    event_queue: &mut EventQueue<CaptureFrameState>,
+    state: &mut CaptureFrameState,
+    linux_dmabuf: &ZwpLinuxDmabufV1,
+    dmabuf_format: DmabufFormat,
+) -> Result<DmabufBuffer, Box<dyn Error>> {
+    let (fd, planes, size) = allocate_udmabuf(dmabuf_format)?;
+
+    let params = linux_dmabuf.create_params(&event_queue.handle(), ());
+    for (plane_idx, plane) in planes.iter().enumerate() {
+        params.add(
+            fd.as_raw_fd(),
+            plane_idx as u32,
+            plane.offset,
+            plane.stride,
+            (DRM_FORMAT_MOD_LINEAR >> 32) as u32,
+            DRM_FORMAT_MOD_LINEAR as u32,
+        );
     }
+    params.create(
+        dmabuf_format.width as i32,
+        dmabuf_format.height as i32,
+        dmabuf_format.format,
+        zwp_linux_buffer_params_v1::Flags::empty(),
+    );
+
+    state.dmabuf_import = None;
+    while state.dmabuf_import.is_none() {
+        event_queue.blocking_dispatch(state)?;
+    }
+    params.destroy();
+
+    match state.dmabuf_import.take() {
+        Some(DmabufImport::Created(buffer)) => Ok(DmabufBuffer {
+            dmabuf_format,
+            modifier: DRM_FORMAT_MOD_LINEAR,
+            planes,
+            size,
+            buffer,
+            fd,
+        }),
+        _ => Err("Compositor failed to import the dmabuf".into()),
+    }
+}
+
+/// Get a FrameCopy instance with screenshot pixel data for any wl_output object.
+///
+/// This sets up a new CaptureSession for a single capture. Use a CaptureSession directly when
+/// capturing repeatedly.
+pub fn capture_output_frame(
+    globals: &mut GlobalList,
+    conn: &mut Connection,
+    cursor_overlay: i32,
+    output: WlOutput,
+    capture_region: Option<CaptureRegion>,
+) -> Result<FrameCopy, Box<dyn Error>> {
+    CaptureSession::new(globals, conn, cursor_overlay)?
+        .capture_output_frame(&output, capture_region)
 }
 
 /// Return a RawFd to a shm file. We use memfd create on linux and shm_open for BSD support.
 /// You don't need to mess around with this function, it is only used by
-/// capture_output_frame.
+/// CaptureSession.
 fn create_shm_fd() -> std::io::Result<RawFd> {
     // Only try memfd on linux and freebsd.
     #[cfg(any(target_os = "linux", target_os = "freebsd"))]
@@ -408,45 +990,77 @@ pub fn write_to_file(
         "Writing to disk with encoding format: {:#?}",
         encoding_format
     );
+
+    // Frames keep the compositor's format, convert a copy without row padding for the encoders.
+    let frame_format = frame_copy.frame_format;
+    let Some(converter) = create_converter(frame_format.format) else {
+        log::error!("Unsupported buffer format: {:?}", frame_format.format);
+        log::error!("You can send a feature request for the above format to the mailing list for wayshot over at https://sr.ht/~shinyzenith/wayshot.");
+        return Err(format!("Unsupported buffer format: {:?}", frame_format.format).into());
+    };
+    let stride = frame_format.width as usize * 4;
+    let mut data = vec![0; stride * frame_format.height as usize];
+    frame_copy.copy_to(&mut data, stride);
+
+    // 10 bit frames keep their depth in 16 bit images, only JPEG is limited to 8 bits.
+    let ten_bit = matches!(
+        frame_format.format,
+        wl_shm::Format::Xbgr2101010 | wl_shm::Format::Abgr2101010
+    );
+    let color_type = if ten_bit && encoding_format != EncodingFormat::Jpg {
+        data = unpack_2101010(&data, frame_format.format == wl_shm::Format::Abgr2101010)
+            .iter()
+            .flat_map(|channel| channel.to_ne_bytes())
+            .collect();
+        ColorType::Rgba16
+    } else {
+        converter.convert_inplace(&mut data)
+    };
+
     match encoding_format {
         EncodingFormat::Jpg => {
             JpegEncoder::new(&mut output_file).write_image(
-                &frame_copy.frame_mmap,
+                &data,
                 frame_copy.frame_format.width,
                 frame_copy.frame_format.height,
-                frame_copy.frame_color_type,
+                color_type,
             )?;
             output_file.flush()?;
         }
         EncodingFormat::Png => {
             PngEncoder::new(&mut output_file).write_image(
-                &frame_copy.frame_mmap,
+                &data,
                 frame_copy.frame_format.width,
                 frame_copy.frame_format.height,
-                frame_copy.frame_color_type,
+                color_type,
             )?;
             output_file.flush()?;
         }
         EncodingFormat::Ppm => {
-            let rgb8_data = if let ColorType::Rgba8 = frame_copy.frame_color_type {
-                let mut data = Vec::with_capacity(
-                    (3 * frame_copy.frame_format.width * frame_copy.frame_format.height) as _,
-                );
-                for chunk in frame_copy.frame_mmap.chunks_exact(4) {
-                    data.extend_from_slice(&chunk[..3]);
+            let (rgb_data, rgb_color_type) = match color_type {
+                ColorType::Rgba8 | ColorType::Rgba16 => {
+                    let bytes_per_pixel = color_type.bytes_per_pixel() as usize;
+                    let mut rgb_data = Vec::with_capacity(data.len() / 4 * 3);
+                    for chunk in data.chunks_exact(bytes_per_pixel) {
+                        rgb_data.extend_from_slice(&chunk[..bytes_per_pixel / 4 * 3]);
+                    }
+                    let rgb_color_type = if color_type == ColorType::Rgba8 {
+                        ColorType::Rgb8
+                    } else {
+                        ColorType::Rgb16
+                    };
+                    (rgb_data, rgb_color_type)
                 }
-                data
-            } else {
-                unimplemented!("Currently only ColorType::Rgba8 is supported")
+                _ => unimplemented!("Currently only ColorType::Rgba8 and Rgba16 are supported"),
             };
 
             PnmEncoder::new(&mut output_file)
                 .with_subtype(pnm::PnmSubtype::Pixmap(pnm::SampleEncoding::Binary))
                 .write_image(
-                    &rgb8_data,
+                    &rgb_data,
                     frame_copy.frame_format.width,
                     frame_copy.frame_format.height,
-                    ColorType::Rgb8,
+                    rgb_color_type,
                 )?;
             output_file.flush()?;
         }
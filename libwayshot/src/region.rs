@@ -0,0 +1,325 @@
+use std::{
+    cmp,
+    error::Error,
+    time::{Duration, Instant},
+};
+
+use wayland_client::protocol::{wl_output::WlOutput, wl_shm::Format};
+
+use crate::{copy_rows, CaptureRegion, CaptureSession, FrameCopy, FrameFormat};
+
+/// Where an output sits in the compositor's logical layout.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputGeometry {
+    pub output: WlOutput,
+    /// Logical position and size of the output.
+    pub geometry: CaptureRegion,
+}
+
+/// The part of a capture region that is covered by one output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputRegion {
+    pub output: WlOutput,
+    /// Area to capture, relative to the output.
+    pub capture_region: CaptureRegion,
+    /// Where the captured area lands in the stitched region.
+    pub canvas_region: CaptureRegion,
+}
+
+/// Clip the region against every output and return the outputs it spans.
+pub fn intersect_region(region: &CaptureRegion, outputs: &[OutputGeometry]) -> Vec<OutputRegion> {
+    outputs
+        .iter()
+        .filter_map(|output| {
+            let geometry = &output.geometry;
+            let clipped = clip(*region, *geometry)?;
+            Some(OutputRegion {
+                output: output.output.clone(),
+                capture_region: CaptureRegion {
+                    x_coordinate: clipped.x_coordinate - geometry.x_coordinate,
+                    y_coordinate: clipped.y_coordinate - geometry.y_coordinate,
+                    ..clipped
+                },
+                canvas_region: CaptureRegion {
+                    x_coordinate: clipped.x_coordinate - region.x_coordinate,
+                    y_coordinate: clipped.y_coordinate - region.y_coordinate,
+                    ..clipped
+                },
+            })
+        })
+        .collect()
+}
+
+/// A 32 bits per pixel image in logical coordinates that output captures are stitched into.
+/// Areas not covered by any output stay black.
+#[derive(Debug, Clone)]
+pub struct Canvas {
+    pub width: u32,
+    pub height: u32,
+    /// Buffer format of the frames blitted into the canvas.
+    pub format: Option<Format>,
+    /// Latest presentation time of the frames blitted into the canvas, on CLOCK_MONOTONIC.
+    pub presentation_time: Option<Duration>,
+    data: Vec<u8>,
+}
+
+impl Canvas {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            format: None,
+            presentation_time: None,
+            data: vec![0; (width * height * 4) as usize],
+        }
+    }
+
+    /// Bytes per row of the canvas.
+    pub fn stride(&self) -> u32 {
+        self.width * 4
+    }
+
+    /// Pixel data of the canvas, `stride * height` bytes.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Copy the canvas into an image with rows of `dst_stride` bytes.
+    pub fn copy_to(&self, dst: &mut [u8], dst_stride: usize) {
+        let stride = self.stride() as usize;
+        copy_rows(
+            &self.data,
+            stride,
+            dst,
+            dst_stride,
+            stride,
+            self.height as usize,
+        );
+    }
+
+    /// Copy a frame into an area of the canvas and return the frame's damage in canvas
+    /// coordinates. Frames of outputs with a scale other than 1 come in at a different size than
+    /// the area and are resampled to fit it. Fails for frames in another format than the ones
+    /// already on the canvas.
+    pub fn blit(
+        &mut self,
+        frame_copy: &FrameCopy,
+        area: CaptureRegion,
+    ) -> Result<Vec<CaptureRegion>, Box<dyn Error>> {
+        let damage = self.blit_data(
+            frame_copy.data(),
+            frame_copy.frame_format,
+            &frame_copy.damage,
+            area,
+        )?;
+        self.presentation_time = self
+            .presentation_time
+            .max(Some(frame_copy.presentation_time));
+        Ok(damage)
+    }
+
+    /// Like blit, for pixel data of `frame_format` with `damage` in buffer coordinates. Parts of
+    /// the area outside the canvas are cropped.
+    pub fn blit_data(
+        &mut self,
+        src: &[u8],
+        frame_format: FrameFormat,
+        damage: &[CaptureRegion],
+        area: CaptureRegion,
+    ) -> Result<Vec<CaptureRegion>, Box<dyn Error>> {
+        // The canvas is handed on as one image of one format, outputs in different formats can't
+        // share it
+        if let Some(format) = self.format.filter(|format| *format != frame_format.format) {
+            return Err(format!(
+                "Can't stitch a {:?} frame into a {format:?} canvas",
+                frame_format.format
+            )
+            .into());
+        }
+
+        let src_stride = frame_format.stride as usize;
+        let (src_width, src_height) = (frame_format.width as usize, frame_format.height as usize);
+        if area.width <= 0 || area.height <= 0 || src_width == 0 || src_height == 0 {
+            return Ok(Vec::new());
+        }
+        let (area_width, area_height) = (area.width as usize, area.height as usize);
+
+        // Clip the area to the canvas, it should always fit but better safe than sorry. The frame
+        // is still scaled to the whole area, only the part on the canvas is copied.
+        let canvas = CaptureRegion {
+            x_coordinate: 0,
+            y_coordinate: 0,
+            width: self.width as i32,
+            height: self.height as i32,
+        };
+        let Some(visible) = clip(area, canvas) else {
+            return Ok(Vec::new());
+        };
+        let (x, y) = (visible.x_coordinate as usize, visible.y_coordinate as usize);
+        let (width, height) = (visible.width as usize, visible.height as usize);
+        // Offset of the visible part in the area
+        let offset_x = (visible.x_coordinate - area.x_coordinate) as usize;
+        let offset_y = (visible.y_coordinate - area.y_coordinate) as usize;
+
+        let stride = self.stride() as usize;
+        if src_width == area_width && src_height == area_height {
+            copy_rows(
+                &src[offset_y * src_stride + offset_x * 4..],
+                src_stride,
+                &mut self.data[y * stride + x * 4..],
+                stride,
+                width * 4,
+                height,
+            );
+        } else {
+            // Nearest neighbour is good enough to bring mixed scale outputs onto one canvas.
+            for row in 0..height {
+                let src_row = &src[(offset_y + row) * src_height / area_height * src_stride..];
+                let dst_row = &mut self.data[(y + row) * stride + x * 4..][..width * 4];
+                for (col, pixel) in dst_row.chunks_exact_mut(4).enumerate() {
+                    let src_col = (offset_x + col) * src_width / area_width;
+                    pixel.copy_from_slice(&src_row[src_col * 4..][..4]);
+                }
+            }
+        }
+        self.format = Some(frame_format.format);
+
+        // Scale the damage from buffer coordinates to the area, rounding outwards, and crop it
+        // like the frame.
+        Ok(damage
+            .iter()
+            .filter_map(|damage| {
+                let x1 = damage.x_coordinate as usize * area_width / src_width;
+                let y1 = damage.y_coordinate as usize * area_height / src_height;
+                let x2 = ((damage.x_coordinate + damage.width) as usize * area_width)
+                    .div_ceil(src_width)
+                    .min(area_width);
+                let y2 = ((damage.y_coordinate + damage.height) as usize * area_height)
+                    .div_ceil(src_height)
+                    .min(area_height);
+                clip(
+                    CaptureRegion {
+                        x_coordinate: area.x_coordinate + x1 as i32,
+                        y_coordinate: area.y_coordinate + y1 as i32,
+                        width: x2.saturating_sub(x1) as i32,
+                        height: y2.saturating_sub(y1) as i32,
+                    },
+                    visible,
+                )
+            })
+            .collect())
+    }
+}
+
+/// The part of `region` inside `bounds`, if any.
+fn clip(region: CaptureRegion, bounds: CaptureRegion) -> Option<CaptureRegion> {
+    let x1 = cmp::max(region.x_coordinate, bounds.x_coordinate);
+    let y1 = cmp::max(region.y_coordinate, bounds.y_coordinate);
+    let x2 = cmp::min(
+        region.x_coordinate + region.width,
+        bounds.x_coordinate + bounds.width,
+    );
+    let y2 = cmp::min(
+        region.y_coordinate + region.height,
+        bounds.y_coordinate + bounds.height,
+    );
+    (x2 > x1 && y2 > y1).then_some(CaptureRegion {
+        x_coordinate: x1,
+        y_coordinate: y1,
+        width: x2 - x1,
+        height: y2 - y1,
+    })
+}
+
+impl CaptureSession {
+    /// Capture every output a region spans and stitch them into the canvas. Returns the damaged
+    /// areas of the canvas, which is all of it.
+    pub fn capture_region(
+        &mut self,
+        output_regions: &[OutputRegion],
+        canvas: &mut Canvas,
+    ) -> Result<Vec<CaptureRegion>, Box<dyn Error>> {
+        // Request all frames first so the compositor can copy them in one go.
+        for output_region in output_regions {
+            let index =
+                self.request_frame(&output_region.output, Some(output_region.capture_region));
+            self.copy_frame(index, false)?;
+        }
+
+        let mut damage = Vec::new();
+        for output_region in output_regions {
+            let index = self
+                .find_frame(
+                    &output_region.output,
+                    Some(output_region.capture_region),
+                    false,
+                )
+                .expect("Requested frame is not pending");
+            while self.state.frames[index].state.is_none() {
+                self.event_queue.blocking_dispatch(&mut self.state)?;
+            }
+            let mut frame_copy = self.finish_frame(index)?;
+            frame_copy.damage = vec![CaptureRegion {
+                x_coordinate: 0,
+                y_coordinate: 0,
+                width: frame_copy.frame_format.width as i32,
+                height: frame_copy.frame_format.height as i32,
+            }];
+            damage.extend(canvas.blit(&frame_copy, output_region.canvas_region)?);
+        }
+        Ok(damage)
+    }
+
+    /// Like capture_region, but only outputs with new content are captured, using
+    /// copy_with_damage. Returns as soon as any output has new content, with the damaged areas of
+    /// the canvas. Nothing is returned if no output changed within `timeout`, requests for the
+    /// other outputs stay pending for the next call.
+    pub fn capture_region_with_damage(
+        &mut self,
+        output_regions: &[OutputRegion],
+        canvas: &mut Canvas,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<CaptureRegion>, Box<dyn Error>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        for output_region in output_regions {
+            let capture_region = Some(output_region.capture_region);
+            if self
+                .find_frame(&output_region.output, capture_region, true)
+                .is_none()
+            {
+                let index = self.request_frame(&output_region.output, capture_region);
+                self.copy_frame(index, true)?;
+            }
+        }
+
+        loop {
+            let mut damage = Vec::new();
+            for output_region in output_regions {
+                let capture_region = Some(output_region.capture_region);
+                let Some(index) = self.find_frame(&output_region.output, capture_region, true)
+                else {
+                    continue;
+                };
+                if self.state.frames[index].state.is_some() {
+                    let frame_copy = self.finish_frame(index)?;
+                    damage.extend(canvas.blit(&frame_copy, output_region.canvas_region)?);
+                }
+            }
+            if !damage.is_empty() {
+                return Ok(damage);
+            }
+
+            match deadline {
+                Some(deadline) => {
+                    if !self.dispatch_until(deadline)? {
+                        return Ok(Vec::new());
+                    }
+                }
+                None => {
+                    self.event_queue.blocking_dispatch(&mut self.state)?;
+                }
+            }
+        }
+    }
+}
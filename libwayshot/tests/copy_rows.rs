@@ -0,0 +1,131 @@
+use std::time::Instant;
+
+use libwayshot::copy_rows;
+
+/// Fill an image of `width` pixels per row with a pattern that is unique per pixel, and the row
+/// padding with a marker that must never show up in a copy.
+fn padded_image(width: usize, height: usize, stride: usize) -> Vec<u8> {
+    let mut image = vec![0xee; stride * height];
+    for (y, row) in image.chunks_mut(stride).enumerate() {
+        for (x, pixel) in row[..width * 4].chunks_exact_mut(4).enumerate() {
+            let value = (y * width + x) as u32;
+            pixel.copy_from_slice(&value.to_le_bytes());
+        }
+    }
+    image
+}
+
+fn assert_same_pixels(src: &[u8], src_stride: usize, dst: &[u8], dst_stride: usize, width: usize) {
+    for (y, (src_row, dst_row)) in src
+        .chunks(src_stride)
+        .zip(dst.chunks(dst_stride))
+        .enumerate()
+    {
+        assert_eq!(
+            src_row[..width * 4],
+            dst_row[..width * 4],
+            "row {y} differs"
+        );
+    }
+}
+
+#[test]
+fn copies_odd_widths_and_padded_strides() {
+    for (width, height) in [(1, 1), (3, 7), (1366, 768), (1921, 33)] {
+        let row_bytes = width * 4;
+        for src_padding in [0, 4, 60, 256] {
+            for dst_padding in [0, 12, 64] {
+                let src_stride = row_bytes + src_padding;
+                let dst_stride = row_bytes + dst_padding;
+                let src = padded_image(width, height, src_stride);
+                let mut dst = vec![0; dst_stride * height];
+
+                copy_rows(&src, src_stride, &mut dst, dst_stride, row_bytes, height);
+
+                assert_same_pixels(&src, src_stride, &dst, dst_stride, width);
+                for row in dst.chunks(dst_stride) {
+                    assert!(row[row_bytes..].iter().all(|byte| *byte == 0));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn copies_last_row_without_padding() {
+    let (width, height, stride) = (5, 4, 32);
+    let row_bytes = width * 4;
+    let mut src = padded_image(width, height, stride);
+    src.truncate(stride * (height - 1) + row_bytes);
+    let mut dst = vec![0; row_bytes * height];
+
+    copy_rows(&src, stride, &mut dst, row_bytes, row_bytes, height);
+
+    assert_same_pixels(&src, stride, &dst, row_bytes, width);
+}
+
+#[test]
+fn clamps_to_the_smaller_image() {
+    let (width, height, stride) = (6, 5, 32);
+    let src = padded_image(width, height, stride);
+
+    // Rows wider than the destination stride are cut to it
+    let dst_stride = 4 * 4;
+    let mut dst = vec![0; dst_stride * height];
+    copy_rows(&src, stride, &mut dst, dst_stride, width * 4, height);
+    assert_same_pixels(&src, stride, &dst, dst_stride, 4);
+
+    // A destination with fewer rows only gets those, the last one without padding
+    let row_bytes = width * 4;
+    let mut dst = vec![0; stride * 2 + row_bytes];
+    copy_rows(&src, stride, &mut dst, stride, row_bytes, height);
+    assert_same_pixels(&src, stride, &dst, stride, width);
+
+    // As does a source with fewer rows, and a tight copy of more rows than there are
+    let mut dst = vec![0; row_bytes * height];
+    copy_rows(
+        &src[..stride * 2],
+        stride,
+        &mut dst,
+        row_bytes,
+        row_bytes,
+        height,
+    );
+    assert_same_pixels(
+        &src[..stride * 2],
+        stride,
+        &dst[..row_bytes * 2],
+        row_bytes,
+        width,
+    );
+    let tight = vec![1; row_bytes * 2];
+    copy_rows(&tight, row_bytes, &mut dst, row_bytes, row_bytes, height);
+    assert_eq!(dst[..row_bytes * 2], tight[..]);
+}
+
+/// Not a precise benchmark, run with `--ignored --nocapture` to see how fast full frames are
+/// copied.
+#[test]
+#[ignore]
+fn benchmark_frame_copies() {
+    const ITERATIONS: u32 = 20;
+
+    for (width, height, src_padding) in [(1920, 1080, 0), (1921, 1080, 60), (3840, 2160, 256)] {
+        let row_bytes = width * 4;
+        let src_stride = row_bytes + src_padding;
+        let src = padded_image(width, height, src_stride);
+        let mut dst = vec![0; row_bytes * height];
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            copy_rows(&src, src_stride, &mut dst, row_bytes, row_bytes, height);
+        }
+        let per_frame = start.elapsed() / ITERATIONS;
+
+        assert_same_pixels(&src, src_stride, &dst, row_bytes, width);
+        println!(
+            "{width}x{height}, stride {src_stride}: {per_frame:?} per frame, {:.0} MiB/s",
+            (row_bytes * height) as f64 / per_frame.as_secs_f64() / (1 << 20) as f64
+        );
+    }
+}
@@ -0,0 +1,228 @@
+use std::os::unix::net::UnixStream;
+
+use libwayshot::{intersect_region, Canvas, CaptureRegion, FrameFormat, OutputGeometry};
+use wayland_client::{
+    protocol::{wl_output::WlOutput, wl_shm::Format},
+    Connection, Proxy,
+};
+
+fn region(x_coordinate: i32, y_coordinate: i32, width: i32, height: i32) -> CaptureRegion {
+    CaptureRegion {
+        x_coordinate,
+        y_coordinate,
+        width,
+        height,
+    }
+}
+
+/// Outputs at the given positions. The proxies are inert, only the geometry matters here.
+fn outputs(geometries: &[CaptureRegion]) -> Vec<OutputGeometry> {
+    let (socket, _) = UnixStream::pair().unwrap();
+    let connection = Connection::from_socket(socket).unwrap();
+    geometries
+        .iter()
+        .map(|geometry| OutputGeometry {
+            output: WlOutput::inert(connection.backend().downgrade()),
+            geometry: *geometry,
+        })
+        .collect()
+}
+
+/// A frame whose pixels hold their own coordinates, with rows padded to `stride` bytes.
+fn frame(width: u32, height: u32, stride: u32) -> (Vec<u8>, FrameFormat) {
+    let mut data = vec![0xee; (stride * height) as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let offset = (y * stride + x * 4) as usize;
+            data[offset..offset + 4].copy_from_slice(&[x as u8, y as u8, 0, 0xff]);
+        }
+    }
+    let frame_format = FrameFormat {
+        format: Format::Xrgb8888,
+        width,
+        height,
+        stride,
+    };
+    (data, frame_format)
+}
+
+fn pixel(canvas: &Canvas, x: u32, y: u32) -> [u8; 4] {
+    let offset = (y * canvas.stride() + x * 4) as usize;
+    canvas.data()[offset..offset + 4].try_into().unwrap()
+}
+
+#[test]
+fn splits_region_across_side_by_side_outputs() {
+    let outputs = outputs(&[region(0, 0, 1920, 1080), region(1920, 0, 1920, 1080)]);
+
+    let output_regions = intersect_region(&region(1800, 100, 200, 50), &outputs);
+
+    assert_eq!(output_regions.len(), 2);
+    assert_eq!(output_regions[0].capture_region, region(1800, 100, 120, 50));
+    assert_eq!(output_regions[0].canvas_region, region(0, 0, 120, 50));
+    assert_eq!(output_regions[1].capture_region, region(0, 100, 80, 50));
+    assert_eq!(output_regions[1].canvas_region, region(120, 0, 80, 50));
+}
+
+#[test]
+fn handles_outputs_left_of_and_above_the_origin() {
+    let outputs = outputs(&[region(-1280, -200, 1280, 1024), region(0, 0, 1920, 1080)]);
+
+    let output_regions = intersect_region(&region(-1280, -200, 3200, 1280), &outputs);
+
+    assert_eq!(output_regions.len(), 2);
+    assert_eq!(output_regions[0].capture_region, region(0, 0, 1280, 1024));
+    assert_eq!(output_regions[0].canvas_region, region(0, 0, 1280, 1024));
+    assert_eq!(output_regions[1].capture_region, region(0, 0, 1920, 1080));
+    assert_eq!(
+        output_regions[1].canvas_region,
+        region(1280, 200, 1920, 1080)
+    );
+}
+
+#[test]
+fn skips_outputs_the_region_misses() {
+    let outputs = outputs(&[region(0, 0, 1920, 1080), region(1920, 0, 1920, 1080)]);
+
+    // Touching the edge of the second output is not overlapping it
+    let output_regions = intersect_region(&region(1000, 500, 920, 580), &outputs);
+    assert_eq!(output_regions.len(), 1);
+    assert_eq!(
+        output_regions[0].capture_region,
+        region(1000, 500, 920, 580)
+    );
+
+    assert!(intersect_region(&region(0, 1080, 100, 100), &outputs).is_empty());
+}
+
+#[test]
+fn clips_region_at_the_edge_of_the_layout() {
+    let outputs = outputs(&[region(0, 0, 1920, 1080)]);
+
+    let output_regions = intersect_region(&region(1800, 1000, 400, 300), &outputs);
+
+    assert_eq!(output_regions.len(), 1);
+    assert_eq!(
+        output_regions[0].capture_region,
+        region(1800, 1000, 120, 80)
+    );
+    assert_eq!(output_regions[0].canvas_region, region(0, 0, 120, 80));
+}
+
+#[test]
+fn blits_frames_side_by_side() {
+    let mut canvas = Canvas::new(8, 4);
+    let (data, frame_format) = frame(4, 4, 20);
+
+    let damage = canvas
+        .blit_data(
+            &data,
+            frame_format,
+            &[region(1, 1, 2, 2)],
+            region(4, 0, 4, 4),
+        )
+        .unwrap();
+
+    assert_eq!(damage, [region(5, 1, 2, 2)]);
+    assert_eq!(canvas.format, Some(Format::Xrgb8888));
+    assert_eq!(pixel(&canvas, 3, 0), [0; 4]);
+    assert_eq!(pixel(&canvas, 4, 0), [0, 0, 0, 0xff]);
+    assert_eq!(pixel(&canvas, 7, 3), [3, 3, 0, 0xff]);
+}
+
+#[test]
+fn resamples_frames_of_scaled_outputs() {
+    let mut canvas = Canvas::new(4, 4);
+    // An output with scale 2 sends twice the pixels of its logical size
+    let (data, frame_format) = frame(8, 8, 32);
+
+    let damage = canvas
+        .blit_data(
+            &data,
+            frame_format,
+            &[region(3, 3, 2, 2)],
+            region(0, 0, 4, 4),
+        )
+        .unwrap();
+
+    // Damage is rounded outwards onto the logical pixels it touches
+    assert_eq!(damage, [region(1, 1, 2, 2)]);
+    assert_eq!(pixel(&canvas, 0, 0), [0, 0, 0, 0xff]);
+    assert_eq!(pixel(&canvas, 1, 2), [2, 4, 0, 0xff]);
+    assert_eq!(pixel(&canvas, 3, 3), [6, 6, 0, 0xff]);
+}
+
+#[test]
+fn crops_frames_that_overhang_the_canvas() {
+    let mut canvas = Canvas::new(4, 4);
+    let (data, frame_format) = frame(4, 4, 16);
+
+    let damage = canvas
+        .blit_data(
+            &data,
+            frame_format,
+            &[region(0, 0, 4, 4)],
+            region(2, 1, 4, 4),
+        )
+        .unwrap();
+
+    // The frame keeps its size, the part past the edge is cut off instead of squeezed in
+    assert_eq!(damage, [region(2, 1, 2, 3)]);
+    assert_eq!(pixel(&canvas, 2, 1), [0, 0, 0, 0xff]);
+    assert_eq!(pixel(&canvas, 3, 3), [1, 2, 0, 0xff]);
+
+    let mut canvas = Canvas::new(4, 4);
+    let damage = canvas
+        .blit_data(
+            &data,
+            frame_format,
+            &[region(0, 0, 1, 1)],
+            region(-2, -1, 4, 4),
+        )
+        .unwrap();
+
+    assert!(damage.is_empty());
+    assert_eq!(pixel(&canvas, 0, 0), [2, 1, 0, 0xff]);
+    assert_eq!(pixel(&canvas, 1, 2), [3, 3, 0, 0xff]);
+    assert_eq!(pixel(&canvas, 2, 0), [0; 4]);
+}
+
+#[test]
+fn crops_scaled_frames_that_overhang_the_canvas() {
+    let mut canvas = Canvas::new(4, 4);
+    let (data, frame_format) = frame(8, 8, 32);
+
+    let damage = canvas
+        .blit_data(
+            &data,
+            frame_format,
+            &[region(0, 0, 8, 8)],
+            region(2, 2, 4, 4),
+        )
+        .unwrap();
+
+    assert_eq!(damage, [region(2, 2, 2, 2)]);
+    assert_eq!(pixel(&canvas, 2, 2), [0, 0, 0, 0xff]);
+    assert_eq!(pixel(&canvas, 3, 3), [2, 2, 0, 0xff]);
+    assert_eq!(pixel(&canvas, 1, 1), [0; 4]);
+}
+
+#[test]
+fn rejects_frames_in_another_format() {
+    let mut canvas = Canvas::new(8, 4);
+    let (data, frame_format) = frame(4, 4, 16);
+    canvas
+        .blit_data(&data, frame_format, &[], region(0, 0, 4, 4))
+        .unwrap();
+
+    let other_format = FrameFormat {
+        format: Format::Xbgr8888,
+        ..frame_format
+    };
+    assert!(canvas
+        .blit_data(&data, other_format, &[], region(4, 0, 4, 4))
+        .is_err());
+    // The canvas keeps its format and the frame is not copied
+    assert_eq!(canvas.format, Some(Format::Xrgb8888));
+    assert_eq!(pixel(&canvas, 4, 0), [0; 4]);
+}
@@ -0,0 +1,74 @@
+use std::io::Cursor;
+
+use image::{codecs::png::PngEncoder, ColorType, ImageEncoder, ImageFormat};
+use libwayshot::unpack_2101010;
+
+/// Pack 10 bit channels and 2 bits of alpha the way Abgr2101010 lays them out in memory.
+fn pack_abgr2101010(r: u32, g: u32, b: u32, a: u32) -> [u8; 4] {
+    (a << 30 | b << 20 | g << 10 | r).to_le_bytes()
+}
+
+/// Channel values that lose precision when truncated to 8 bits, plus the extremes.
+const PIXELS: [[u32; 4]; 6] = [
+    [0, 0, 0, 0],
+    [1023, 1023, 1023, 3],
+    [1, 2, 3, 1],
+    [513, 514, 515, 2],
+    [1021, 0, 767, 3],
+    [341, 682, 1022, 0],
+];
+
+fn packed_pixels() -> Vec<u8> {
+    PIXELS
+        .iter()
+        .flat_map(|[r, g, b, a]| pack_abgr2101010(*r, *g, *b, *a))
+        .collect()
+}
+
+#[test]
+fn unpacks_10_bit_channels_losslessly() {
+    let pixels = unpack_2101010(&packed_pixels(), true);
+
+    assert_eq!(pixels.len(), PIXELS.len() * 4);
+    for (unpacked, [r, g, b, a]) in pixels.chunks_exact(4).zip(PIXELS) {
+        assert_eq!(
+            [unpacked[0] >> 6, unpacked[1] >> 6, unpacked[2] >> 6],
+            [r as u16, g as u16, b as u16]
+        );
+        assert_eq!(unpacked[3], a as u16 * 0x5555);
+    }
+    // Full scale maps onto full scale
+    assert_eq!(pixels[4..8], [u16::MAX; 4]);
+    assert_eq!(pixels[..4], [0; 4]);
+}
+
+#[test]
+fn padding_becomes_opaque_alpha() {
+    let pixels = unpack_2101010(&packed_pixels(), false);
+
+    assert!(pixels.chunks_exact(4).all(|pixel| pixel[3] == u16::MAX));
+}
+
+#[test]
+fn round_trips_through_16_bit_png() {
+    let pixels = unpack_2101010(&packed_pixels(), true);
+    let bytes: Vec<u8> = pixels
+        .iter()
+        .flat_map(|channel| channel.to_ne_bytes())
+        .collect();
+
+    let mut png = Vec::new();
+    PngEncoder::new(&mut png)
+        .write_image(&bytes, PIXELS.len() as u32, 1, ColorType::Rgba16)
+        .unwrap();
+    let decoded = image::load(Cursor::new(png), ImageFormat::Png)
+        .unwrap()
+        .into_rgba16();
+
+    for (pixel, [r, g, b, _]) in decoded.pixels().zip(PIXELS) {
+        assert_eq!(
+            [pixel[0] >> 6, pixel[1] >> 6, pixel[2] >> 6],
+            [r as u16, g as u16, b as u16]
+        );
+    }
+}
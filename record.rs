@@ -0,0 +1,59 @@
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("64k"), Ok(64 << 10));
+        assert_eq!(parse_size("500M"), Ok(500 << 20));
+        assert_eq!(parse_size(" 2G "), Ok(2 << 30));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        for size in ["", "0", "0M", "M", "-1", "1.5G", "10T", "20000000000G"] {
+            assert!(parse_size(size).is_err(), "{size} parsed");
+        }
+    }
+
+    #[test]
+    fn fills_in_sequence() {
+        assert_eq!(
+            segment_location(Path::new("desktop-{sequence}.mkv"), 7),
+            PathBuf::from("desktop-00007.mkv")
+        );
+    }
+
+    #[test]
+    fn fills_in_timestamp() {
+        let location = segment_location(Path::new("desktop-{timestamp}.mkv"), 7);
+        let location = location.to_str().unwrap();
+        let timestamp = location
+            .strip_prefix("desktop-")
+            .and_then(|location| location.strip_suffix(".mkv"))
+            .unwrap();
+        // %Y%m%d-%H%M%S
+        assert_eq!(timestamp.len(), 15, "{location}");
+        assert!(timestamp
+            .chars()
+            .enumerate()
+            .all(|(index, c)| if index == 8 {
+                c == '-'
+            } else {
+                c.is_ascii_digit()
+            }));
+    }
+
+    #[test]
+    fn appends_sequence_without_placeholders() {
+        assert_eq!(
+            segment_location(Path::new("desktop.mkv"), 12),
+            PathBuf::from("desktop-00012.mkv")
+        );
+        assert_eq!(
+            segment_location(Path::new("recordings.d/desktop"), 3),
+            PathBuf::from("recordings.d/desktop-00003")
+        );
+    }
+}
@@ -0,0 +1,56 @@
+use std::borrow::Cow;
+
+/// Split `host:port` into the host and the port. The port is separated from the host by the last
+/// colon, IPv6 addresses come in brackets, which are dropped. The host may be empty.
+pub fn parse(s: &str) -> Option<(&str, u16)> {
+    let (host, port) = s.rsplit_once(':')?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    Some((host, port.parse().ok()?))
+}
+
+/// The host as URLs carry it, IPv6 addresses in brackets.
+pub fn url_host(host: &str) -> Cow<'_, str> {
+    if host.contains(':') {
+        Cow::Owned(format!("[{host}]"))
+    } else {
+        Cow::Borrowed(host)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(parse("127.0.0.1:8554"), Some(("127.0.0.1", 8554)));
+        assert_eq!(parse("localhost:8080"), Some(("localhost", 8080)));
+        assert_eq!(parse("[::1]:9000"), Some(("::1", 9000)));
+        assert_eq!(parse("[fe80::1%eth0]:9000"), Some(("fe80::1%eth0", 9000)));
+        assert_eq!(parse(":9000"), Some(("", 9000)));
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        for address in [
+            "localhost",
+            "localhost:",
+            "localhost:port",
+            "[::1]:65536",
+            "[::1]",
+        ] {
+            assert_eq!(parse(address), None, "{address} parsed");
+        }
+    }
+
+    #[test]
+    fn brackets_ipv6_hosts() {
+        assert_eq!(url_host("127.0.0.1"), "127.0.0.1");
+        assert_eq!(url_host("localhost"), "localhost");
+        assert_eq!(url_host("::1"), "[::1]");
+        assert_eq!(url_host(""), "");
+    }
+}
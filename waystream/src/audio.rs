@@ -0,0 +1,476 @@
+use std::{
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::Error as aError;
+use gstreamer::prelude::*;
+
+use crate::{make_element, sdp};
+
+/// Sample rate everything is captured and encoded at, Opus only takes a few rates and 48 kHz is
+/// the one every encoder handles.
+pub const SAMPLE_RATE: i32 = 48000;
+pub const CHANNELS: i32 = 2;
+
+/// Highest gain in dB, the most the volume of an audiomixer pad goes up to.
+const MAX_GAIN: f64 = 20.0;
+
+/// How long a noise gate stays open after its input fell below the threshold, so the quiet ends
+/// of words aren't cut off.
+const GATE_HOLD_FRAMES: usize = SAMPLE_RATE as usize / 5;
+
+/// Where the audio of the stream comes from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioSource {
+    /// What the desktop plays, the monitor of the default output device.
+    Monitor,
+    /// A sine tone, to check a stream without any sound going on.
+    Test { frequency: f64 },
+    /// A PulseAudio or PipeWire source by name.
+    Device(String),
+}
+
+impl FromStr for AudioSource {
+    type Err = aError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err(aError::msg("Empty audio device")),
+            "monitor" => Ok(AudioSource::Monitor),
+            "test" => Ok(AudioSource::Test { frequency: 440.0 }),
+            device => Ok(AudioSource::Device(device.to_string())),
+        }
+    }
+}
+
+/// One source of the mix with its settings, parsed from `source[,gain=DB][,mute][,gate=DBFS]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioInput {
+    pub source: AudioSource,
+    /// Gain in dB.
+    pub gain: f64,
+    pub mute: bool,
+    /// Level in dBFS below which the input is silenced, to keep the noise floor of a microphone
+    /// out of the mix.
+    pub gate: Option<f64>,
+}
+
+impl FromStr for AudioInput {
+    type Err = aError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Device names can hold colons but no commas
+        let mut options = s.split(',');
+        let mut input = AudioInput {
+            source: options.next().unwrap_or_default().parse()?,
+            gain: 0.0,
+            mute: false,
+            gate: None,
+        };
+
+        let decibels = |value: &str| {
+            value
+                .trim_end_matches("dBFS")
+                .trim_end_matches("dB")
+                .parse::<f64>()
+                .ok()
+                .filter(|value| value.is_finite())
+        };
+        for option in options {
+            match option.split_once('=') {
+                None if option == "mute" => input.mute = true,
+                Some(("gain", gain)) => {
+                    input.gain =
+                        decibels(gain)
+                            .filter(|gain| *gain <= MAX_GAIN)
+                            .ok_or_else(|| {
+                                aError::msg(format!(
+                                    "Invalid gain {gain}, expected up to {MAX_GAIN} dB"
+                                ))
+                            })?
+                }
+                Some(("gate", gate)) => {
+                    input.gate =
+                        Some(decibels(gate).filter(|gate| *gate <= 0.0).ok_or_else(|| {
+                            aError::msg(format!("Invalid gate {gate}, expected dBFS up to 0"))
+                        })?)
+                }
+                _ => {
+                    return Err(aError::msg(format!(
+                        "Unknown audio option {option}, expected gain=DB, mute or gate=DBFS"
+                    )))
+                }
+            }
+        }
+        Ok(input)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioCodec {
+    Opus,
+    Aac,
+}
+
+impl AudioCodec {
+    pub const ALL: [AudioCodec; 2] = [AudioCodec::Opus, AudioCodec::Aac];
+
+    /// Name of the codec on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            AudioCodec::Opus => "opus",
+            AudioCodec::Aac => "aac",
+        }
+    }
+
+    /// Encoders for the codec, in order of preference.
+    pub fn encoders(self) -> &'static [&'static str] {
+        match self {
+            AudioCodec::Opus => &["opusenc"],
+            AudioCodec::Aac => &["fdkaacenc", "avenc_aac", "voaacenc"],
+        }
+    }
+
+    pub fn parser(self) -> &'static str {
+        match self {
+            AudioCodec::Opus => "opusparse",
+            AudioCodec::Aac => "aacparse",
+        }
+    }
+
+    pub fn payloader(self) -> &'static str {
+        match self {
+            AudioCodec::Opus => "rtpopuspay",
+            AudioCodec::Aac => "rtpmp4gpay",
+        }
+    }
+
+    /// Muxers that can hold the codec.
+    pub fn muxers(self) -> &'static [&'static str] {
+        match self {
+            AudioCodec::Opus => &["mpegtsmux", "mp4mux", "matroskamux", "webmmux"],
+            AudioCodec::Aac => &["mpegtsmux", "mp4mux", "matroskamux"],
+        }
+    }
+
+    /// RTP encoding of the codec for the SDP. AAC needs the configuration of the encoder in
+    /// there, which isn't known up front.
+    pub fn rtp_encoding(self) -> Option<&'static str> {
+        match self {
+            AudioCodec::Opus => Some("OPUS/48000/2"),
+            AudioCodec::Aac => None,
+        }
+    }
+
+    /// First encoder for the codec that is installed.
+    pub fn available_encoder(self) -> Option<&'static str> {
+        self.encoders()
+            .iter()
+            .copied()
+            .find(|encoder| gstreamer::ElementFactory::find(encoder).is_some())
+    }
+}
+
+impl fmt::Display for AudioCodec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for AudioCodec {
+    type Err = aError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        AudioCodec::ALL
+            .into_iter()
+            .find(|codec| codec.name() == s)
+            .ok_or_else(|| aError::msg(format!("Unknown audio codec {s}")))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AudioOptions {
+    /// Sources mixed into the audio of the stream.
+    pub inputs: Vec<AudioInput>,
+    pub codec: AudioCodec,
+    /// Target bitrate in kbit/s.
+    pub bitrate: u32,
+}
+
+/// Create the live source and the elements bringing its audio to the sample rate and channels
+/// of the encoders. Live sources timestamp on the pipeline clock, like the appsrc does the video,
+/// so both stay in sync without further help.
+pub fn make_source(source: &AudioSource) -> Result<Vec<gstreamer::Element>, aError> {
+    let src = match source {
+        AudioSource::Test { frequency } => gstreamer::ElementFactory::make("audiotestsrc")
+            .property("is-live", true)
+            .property("freq", frequency)
+            .property("volume", 0.25f64)
+            .build()?,
+        AudioSource::Monitor | AudioSource::Device(_) => {
+            if gstreamer::ElementFactory::find("pulsesrc").is_some() {
+                // PipeWire serves pulsesrc as well, so it is preferred for its device names
+                let device = match source {
+                    AudioSource::Device(device) => device.as_str(),
+                    _ => "@DEFAULT_MONITOR@",
+                };
+                gstreamer::ElementFactory::make("pulsesrc")
+                    .property("device", device)
+                    .build()?
+            } else {
+                let src = make_element("pipewiresrc")?;
+                match source {
+                    AudioSource::Device(device) => src.set_property("target-object", device),
+                    _ => src.set_property(
+                        "stream-properties",
+                        gstreamer::Structure::builder("props")
+                            .field("stream.capture.sink", "true")
+                            .build(),
+                    ),
+                }
+                src
+            }
+        }
+    };
+
+    log::info!("Capturing audio from {source:?}");
+    Ok(vec![
+        src,
+        make_element("audioconvert")?,
+        make_element("audioresample")?,
+        make_caps()?,
+    ])
+}
+
+/// Capsfilter for the format everything is mixed in, float samples to mix without clipping.
+fn make_caps() -> Result<gstreamer::Element, aError> {
+    Ok(gstreamer::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gstreamer::Caps::builder("audio/x-raw")
+                .field("format", "F32LE")
+                .field("rate", SAMPLE_RATE)
+                .field("channels", CHANNELS)
+                .build(),
+        )
+        .build()?)
+}
+
+/// Mix the inputs with their gain, mute and noise gate into one stream. Everything is added to
+/// the pipeline, the returned element hands out the mix.
+pub fn make_mix(
+    pipeline: &gstreamer::Pipeline,
+    inputs: &[AudioInput],
+) -> Result<gstreamer::Element, aError> {
+    let mixer = make_element("audiomixer")?;
+    let caps = make_caps()?;
+    pipeline.add_many(&[&mixer, &caps])?;
+    mixer.link(&caps)?;
+
+    for input in inputs {
+        let elements = make_source(&input.source)?;
+        let elements = elements.iter().collect::<Vec<_>>();
+        pipeline.add_many(&elements)?;
+        gstreamer::Element::link_many(&elements)?;
+
+        let src_pad = elements.last().unwrap().static_pad("src").unwrap();
+        if let Some(gate) = input.gate {
+            add_noise_gate(&src_pad, gate);
+        }
+        let sink_pad = mixer
+            .request_pad_simple("sink_%u")
+            .ok_or_else(|| aError::msg("audiomixer without sink pads"))?;
+        sink_pad.set_property("volume", 10f64.powf(input.gain / 20.0));
+        sink_pad.set_property("mute", input.mute);
+        src_pad.link(&sink_pad)?;
+    }
+    Ok(caps)
+}
+
+/// Silence the float samples passing the pad while their level stays below `threshold` dBFS.
+fn add_noise_gate(pad: &gstreamer::Pad, threshold: f64) {
+    let threshold = 10f64.powf(threshold / 20.0);
+    // The gate starts closed, it only opens once there is something to hear
+    let quiet_frames = AtomicUsize::new(GATE_HOLD_FRAMES);
+    pad.add_probe(gstreamer::PadProbeType::BUFFER, move |_, info| {
+        let Some(gstreamer::PadProbeData::Buffer(buffer)) = &mut info.data else {
+            return gstreamer::PadProbeReturn::Ok;
+        };
+        let Ok(mut map) = buffer.make_mut().map_writable() else {
+            return gstreamer::PadProbeReturn::Ok;
+        };
+        let samples = map.len() / 4;
+        if samples == 0 {
+            return gstreamer::PadProbeReturn::Ok;
+        }
+
+        let power = map
+            .chunks_exact(4)
+            .map(|sample| f64::from(f32::from_le_bytes(sample.try_into().unwrap())).powi(2))
+            .sum::<f64>()
+            / samples as f64;
+        let quiet = if power.sqrt() >= threshold {
+            0
+        } else {
+            (quiet_frames.load(Ordering::Relaxed) + samples / CHANNELS as usize)
+                .min(GATE_HOLD_FRAMES)
+        };
+        quiet_frames.store(quiet, Ordering::Relaxed);
+        if quiet >= GATE_HOLD_FRAMES {
+            map.fill(0);
+        }
+        gstreamer::PadProbeReturn::Ok
+    });
+}
+
+/// Create the elements that turn the mix into an encoded, parsed stream of the codec.
+pub fn make_encoder(codec: AudioCodec, bitrate: u32) -> Result<Vec<gstreamer::Element>, aError> {
+    let factory = codec.available_encoder().ok_or_else(|| {
+        aError::msg(format!(
+            "No encoder for {codec} available, install one of {}",
+            codec.encoders().join(", ")
+        ))
+    })?;
+
+    let encoder = make_element(factory)?;
+    // The encoders disagree on the integer type of their bitrate
+    encoder.set_property_from_str("bitrate", &(bitrate * 1000).to_string());
+
+    // Encoders take different sample formats, each branch of the tee converts for its own
+    Ok(vec![
+        make_element("audioconvert")?,
+        encoder,
+        make_element(codec.parser())?,
+    ])
+}
+
+/// Create the RTP payloader for the codec.
+pub fn make_payloader(codec: AudioCodec) -> Result<gstreamer::Element, aError> {
+    let payloader = make_element(codec.payloader())?;
+    payloader.set_property("pt", sdp::AUDIO_PAYLOAD_TYPE);
+    Ok(payloader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_input(frequency: f64) -> AudioInput {
+        AudioInput {
+            source: AudioSource::Test { frequency },
+            gain: 0.0,
+            mute: false,
+            gate: None,
+        }
+    }
+
+    /// Run the mix of the inputs for a second and return the left channel of its second half,
+    /// once the mixer settled.
+    fn mix(inputs: &[AudioInput]) -> Vec<f64> {
+        gstreamer::init().unwrap();
+        let pipeline = gstreamer::Pipeline::default();
+        let mix = make_mix(&pipeline, inputs).unwrap();
+        let appsink = gstreamer_app::AppSink::builder().sync(false).build();
+        pipeline.add(&appsink).unwrap();
+        mix.link(&appsink).unwrap();
+        pipeline.set_state(gstreamer::State::Playing).unwrap();
+
+        let mut left = Vec::new();
+        while left.len() < SAMPLE_RATE as usize {
+            let sample = appsink.pull_sample().expect("The mix ended early");
+            let map = sample.buffer().unwrap().map_readable().unwrap();
+            left.extend(
+                map.chunks_exact(4 * CHANNELS as usize)
+                    .map(|frame| f64::from(f32::from_le_bytes(frame[..4].try_into().unwrap()))),
+            );
+        }
+        pipeline.set_state(gstreamer::State::Null).unwrap();
+        left[SAMPLE_RATE as usize / 2..SAMPLE_RATE as usize].to_vec()
+    }
+
+    /// Amplitude of a tone in the samples, which have to span a whole number of its periods.
+    fn amplitude(samples: &[f64], frequency: f64) -> f64 {
+        let (mut re, mut im) = (0.0, 0.0);
+        for (n, sample) in samples.iter().enumerate() {
+            let phase = 2.0 * std::f64::consts::PI * frequency * n as f64 / f64::from(SAMPLE_RATE);
+            re += sample * phase.cos();
+            im += sample * phase.sin();
+        }
+        2.0 * (re * re + im * im).sqrt() / samples.len() as f64
+    }
+
+    fn assert_amplitude(samples: &[f64], frequency: f64, expected: f64) {
+        let amplitude = amplitude(samples, frequency);
+        assert!(
+            (amplitude - expected).abs() <= 0.1 * expected + 0.005,
+            "Expected {frequency} Hz at {expected}, got {amplitude}"
+        );
+    }
+
+    #[test]
+    fn parses_inputs() {
+        let input: AudioInput = "bluez_input.00:11:22,gain=-6dB,mute,gate=-45"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            input,
+            AudioInput {
+                source: AudioSource::Device(String::from("bluez_input.00:11:22")),
+                gain: -6.0,
+                mute: true,
+                gate: Some(-45.0),
+            }
+        );
+        assert_eq!(
+            "monitor".parse::<AudioInput>().unwrap().source,
+            AudioSource::Monitor
+        );
+        assert!("monitor,gain=30".parse::<AudioInput>().is_err());
+        assert!("monitor,gate=3".parse::<AudioInput>().is_err());
+        assert!("monitor,loud".parse::<AudioInput>().is_err());
+    }
+
+    #[test]
+    fn mixes_two_tones_with_their_gain() {
+        let quiet = AudioInput {
+            gain: -20.0 * 2f64.log10(),
+            ..test_input(1000.0)
+        };
+        let samples = mix(&[test_input(440.0), quiet]);
+
+        // Test tones have an amplitude of 0.25
+        assert_amplitude(&samples, 440.0, 0.25);
+        assert_amplitude(&samples, 1000.0, 0.125);
+    }
+
+    #[test]
+    fn mutes_an_input() {
+        let muted = AudioInput {
+            mute: true,
+            ..test_input(1000.0)
+        };
+        let samples = mix(&[test_input(440.0), muted]);
+
+        assert_amplitude(&samples, 440.0, 0.25);
+        assert_amplitude(&samples, 1000.0, 0.0);
+    }
+
+    #[test]
+    fn gates_an_input_below_the_threshold() {
+        // The tone is about -15 dBFS
+        let gated = AudioInput {
+            gate: Some(-10.0),
+            ..test_input(440.0)
+        };
+        let open = AudioInput {
+            gate: Some(-30.0),
+            ..test_input(1000.0)
+        };
+        let samples = mix(&[gated, open]);
+
+        assert_amplitude(&samples, 440.0, 0.0);
+        assert_amplitude(&samples, 1000.0, 0.25);
+    }
+}
@@ -1,4 +1,12 @@
-use clap::{arg, Command};
+use clap::{
+    arg, builder::PossibleValuesParser, builder::TypedValueParser, ArgAction, ArgGroup, Command,
+};
+
+use crate::{
+    audio::AudioCodec,
+    encoder::{Codec, MAX_BITRATE, PRESETS},
+    record::parse_size,
+};
 
 // https://github.com/clap-rs/clap/issues/4869
 // 4.0 regression: dashes are not accepted any more #4869
@@ -15,17 +23,59 @@ pub fn set_flags() -> Command {
                 .help("Enable debug mode"),
         )
         .arg(
-            arg!(--slurp <GEOMETRY>)
+            arg!(--slurp [GEOMETRY])
                 .required(false)
-                .num_args(1)
-                .help("Select a portion of display to stream using slurp"),
+                .num_args(0..=1)
+                .help("Select a portion of display to stream, as \"x,y wxh\" or \"x y w h\". Runs slurp if no geometry is given"),
         )
         .arg(
             arg!(--"show-fps")
                 .long("show-fps")
                 .required(false)
                 .num_args(0)
-                .help("Show framerate"),
+                .help("Show framerate, as an overlay on the preview or in the log"),
+        )
+        .arg(
+            arg!(--preview [SINK])
+                .required(false)
+                .num_args(0..=1)
+                .default_missing_value("wayland")
+                .value_parser(["wayland", "auto", "fake"])
+                .help("Show a local preview with waylandsink, autovideosink or fakesink (default: wayland)"),
+        )
+        .arg(
+            arg!(--fps <FPS>)
+                .required(false)
+                .num_args(1)
+                .value_parser(clap::value_parser!(u32).range(1..=240))
+                .help("Set the target framerate (default: 30)"),
+        )
+        .arg(
+            arg!(--vfr)
+                .required(false)
+                .num_args(0)
+                .help("Only send frames when the screen content changed, --fps becomes the maximum rate"),
+        )
+        .arg(
+            arg!(--keepalive <MILLISECONDS>)
+                .required(false)
+                .num_args(1)
+                .requires("vfr")
+                .default_value("1000")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Repeat the last frame after this long without changes in variable frame rate mode"),
+        )
+        .arg(
+            arg!(--damage)
+                .required(false)
+                .num_args(0)
+                .help("Only copy frames when the screen content changed, repeating the last one otherwise"),
+        )
+        .arg(
+            arg!(--dmabuf)
+                .required(false)
+                .num_args(0)
+                .help("Capture into dmabufs and pass them on without copying, if supported"),
         )
         .arg(
             arg!(--cursor)
@@ -36,17 +86,240 @@ pub fn set_flags() -> Command {
         .arg(
             arg!(--"udp-host" <UDP_HOST>)
                 .long("udp-host")
-                .required(true)
+                .required_unless_present_any(["hls", "rtsp", "webrtc", "srt", "record"])
+                .requires("udp-port")
                 .num_args(1)
-                .help("Set the host to stream to"),
+                .help("Set the host to stream to, an IPv4 or IPv6 address or a hostname"),
         )
         .arg(
             arg!(--"udp-port" <UDP_PORT>)
                 .long("udp-port")
-                .required(true)
+                .required_unless_present_any(["hls", "rtsp", "webrtc", "srt", "record"])
+                .requires("udp-host")
                 .num_args(1)
+                .value_parser(clap::value_parser!(u16))
                 .help("Set the port to stream to"),
         )
+        .arg(
+            arg!(--"multicast-ttl" <TTL>)
+                .long("multicast-ttl")
+                .required(false)
+                .num_args(1)
+                .value_parser(clap::value_parser!(u8))
+                .help("Set the time to live of multicast packets"),
+        )
+        .arg(
+            arg!(--"multicast-iface" <INTERFACE>)
+                .long("multicast-iface")
+                .required(false)
+                .num_args(1)
+                .help("Set the network interface to send multicast packets on"),
+        )
+        .arg(
+            arg!(--rtp)
+                .required(false)
+                .requires("udp-host")
+                .num_args(0)
+                .help("Encode the stream and send it over RTP instead of raw frames"),
+        )
+        .arg(
+            arg!(--mpegts)
+                .required(false)
+                .requires("udp-host")
+                .num_args(0)
+                .help("Mux the encoded stream into MPEG-TS, sent as 7 x 188 byte datagrams or with --rtp as RTP MP2T"),
+        )
+        .arg(
+            arg!(--codec <CODEC>)
+                .required(false)
+                .num_args(1)
+                .value_parser(
+                    PossibleValuesParser::new(Codec::ALL.map(Codec::name))
+                        .map(|codec| codec.parse::<Codec>().unwrap()),
+                )
+                .help("Set the video codec, one of h264, h265, vp8, vp9, av1 (default: h264)"),
+        )
+        .arg(
+            arg!(--bitrate <KBITS>)
+                .required(false)
+                .num_args(1)
+                .value_parser(clap::value_parser!(u32).range(1..=i64::from(MAX_BITRATE)))
+                .help("Set the encoder bitrate in kbit/s (default: 2048)"),
+        )
+        .arg(
+            arg!(--"keyframe-interval" <FRAMES>)
+                .long("keyframe-interval")
+                .required(false)
+                .num_args(1)
+                .value_parser(clap::value_parser!(u32).range(..=i64::from(i32::MAX)))
+                .help("Set the maximum number of frames between keyframes (default: 60)"),
+        )
+        .arg(
+            arg!(--preset <PRESET>)
+                .required(false)
+                .num_args(1)
+                .value_parser(PRESETS)
+                .help("Set the encoder speed preset (default: ultrafast)"),
+        )
+        .arg(
+            arg!(--"bit-depth" <BITS>)
+                .long("bit-depth")
+                .required(false)
+                .num_args(1)
+                .value_parser(
+                    PossibleValuesParser::new(["8", "10"])
+                        .map(|bits| bits.parse::<u32>().unwrap()),
+                )
+                .help("Set the bits per channel of the encoded video, 10 needs h265 or av1 (default: 8)"),
+        )
+        .arg(
+            arg!(--sdp <FILE>)
+                .required(false)
+                .num_args(1)
+                .value_parser(clap::value_parser!(std::path::PathBuf))
+                .help("Write the SDP describing the RTP stream to this file (default: waystream.sdp)"),
+        )
+        .arg(
+            arg!(--hls <DIR>)
+                .required(false)
+                .num_args(1)
+                .value_parser(clap::value_parser!(std::path::PathBuf))
+                .help("Write an H.264 HLS stream to this directory"),
+        )
+        .arg(
+            arg!(--"hls-segment-duration" <SECONDS>)
+                .long("hls-segment-duration")
+                .required(false)
+                .num_args(1)
+                .default_value("2")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .help("Set the target duration of HLS segments"),
+        )
+        .arg(
+            arg!(--"hls-playlist-length" <SEGMENTS>)
+                .long("hls-playlist-length")
+                .required(false)
+                .num_args(1)
+                .default_value("5")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .help("Set the number of segments in the HLS playlist"),
+        )
+        .arg(
+            arg!(--"hls-location" <PATTERN>)
+                .long("hls-location")
+                .required(false)
+                .conflicts_with("hls-ll")
+                .num_args(1)
+                .help("Set the HLS segment file name pattern (default: segment%05d.ts or segment%05d.m4s)"),
+        )
+        .arg(
+            arg!(--"hls-fmp4")
+                .long("hls-fmp4")
+                .required(false)
+                .num_args(0)
+                .help("Write fragmented MP4 HLS segments instead of MPEG-TS"),
+        )
+        .arg(
+            arg!(--"hls-ll")
+                .long("hls-ll")
+                .required(false)
+                .requires("hls")
+                .num_args(0)
+                .help("Write low latency HLS with parts of each segment, always as fragmented MP4"),
+        )
+        .arg(
+            arg!(--"hls-part-duration" <MILLISECONDS>)
+                .long("hls-part-duration")
+                .required(false)
+                .requires("hls-ll")
+                .num_args(1)
+                .default_value("500")
+                .value_parser(clap::value_parser!(u32).range(100..))
+                .help("Set the target duration of low latency HLS parts"),
+        )
+        .arg(
+            arg!(--rtsp <ADDRESS>)
+                .required(false)
+                .num_args(1)
+                .help("Serve the encoded stream over RTSP at address:port/path, e.g. 0.0.0.0:8554/desktop"),
+        )
+        .arg(
+            arg!(--webrtc <ADDRESS>)
+                .required(false)
+                .num_args(1)
+                .help("Serve a viewer page and WebRTC signalling at address:port, e.g. 127.0.0.1:8080"),
+        )
+        .arg(
+            arg!(--srt <URI>)
+                .required(false)
+                .num_args(1)
+                .help("Send the stream as MPEG-TS over SRT, e.g. srt://192.168.1.10:9000 or srt://:9000?mode=listener&latency=200&passphrase=..."),
+        )
+        .arg(
+            arg!(--record <FILE>)
+                .required(false)
+                .num_args(1)
+                .value_parser(clap::value_parser!(std::path::PathBuf))
+                .help("Record the encoded stream to a .mp4, .mkv or .webm file"),
+        )
+        .arg(
+            arg!(--"segment-duration" <SECONDS>)
+                .long("segment-duration")
+                .required(false)
+                .num_args(1)
+                .requires("record")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Split the recording into segments of this many seconds"),
+        )
+        .arg(
+            arg!(--"segment-size" <SIZE>)
+                .long("segment-size")
+                .required(false)
+                .num_args(1)
+                .requires("record")
+                .value_parser(parse_size)
+                .help("Split the recording into segments of this size, e.g. 500M"),
+        )
+        .arg(
+            arg!(--"max-segments" <SEGMENTS>)
+                .long("max-segments")
+                .required(false)
+                .num_args(1)
+                .requires("segment-limit")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .help("Delete the oldest recording segments to keep at most this many, needs --segment-duration or --segment-size"),
+        )
+        .group(
+            ArgGroup::new("segment-limit")
+                .args(["segment-duration", "segment-size"])
+                .multiple(true),
+        )
+        .arg(
+            arg!(--audio <SOURCE>)
+                .required(false)
+                .num_args(1)
+                .action(ArgAction::Append)
+                .help("Mix in audio from a device name, monitor for what the desktop plays, test for a tone or none, followed by ,gain=DB ,mute or ,gate=DBFS (default: none)"),
+        )
+        .arg(
+            arg!(--"audio-codec" <CODEC>)
+                .long("audio-codec")
+                .required(false)
+                .num_args(1)
+                .value_parser(
+                    PossibleValuesParser::new(AudioCodec::ALL.map(AudioCodec::name))
+                        .map(|codec| codec.parse::<AudioCodec>().unwrap()),
+                )
+                .help("Set the audio codec, one of opus, aac (default: opus)"),
+        )
+        .arg(
+            arg!(--"audio-bitrate" <KBITS>)
+                .long("audio-bitrate")
+                .required(false)
+                .num_args(1)
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .help("Set the audio bitrate in kbit/s (default: 128)"),
+        )
         .arg(
             arg!(--height <TARGET_HEIGHT>)
                 .required(false)
@@ -72,7 +345,7 @@ pub fn set_flags() -> Command {
                 .required(false)
                 .num_args(1)
                 .conflicts_with("slurp")
-                .help("Select a particular display to stream (Not implemented yet)"),
+                .help("Select a particular display to stream"),
         );
     app
 }
@@ -0,0 +1,82 @@
+use anyhow::Error as aError;
+use gstreamer::glib::{self, translate::IntoGlib};
+use gstreamer_allocators::DmaBufAllocator;
+use gstreamer_video::{VideoFormat, VideoFrameFlags, VideoInfo, VideoMeta};
+use libwayshot::DmabufFrame;
+
+const fn fourcc(code: &[u8; 4]) -> u32 {
+    (code[0] as u32) | (code[1] as u32) << 8 | (code[2] as u32) << 16 | (code[3] as u32) << 24
+}
+
+const DRM_FORMAT_XRGB8888: u32 = fourcc(b"XR24");
+const DRM_FORMAT_ARGB8888: u32 = fourcc(b"AR24");
+const DRM_FORMAT_XBGR8888: u32 = fourcc(b"XB24");
+const DRM_FORMAT_ABGR8888: u32 = fourcc(b"AB24");
+
+/// Map the DRM fourcc of a dmabuf frame to the matching GStreamer video format.
+pub fn video_format(frame: &DmabufFrame) -> Option<VideoFormat> {
+    match frame.format {
+        DRM_FORMAT_XRGB8888 => Some(VideoFormat::Bgrx),
+        DRM_FORMAT_ARGB8888 => Some(VideoFormat::Bgra),
+        DRM_FORMAT_XBGR8888 => Some(VideoFormat::Rgbx),
+        DRM_FORMAT_ABGR8888 => Some(VideoFormat::Rgba),
+        _ => None,
+    }
+}
+
+/// Build `video/x-raw(memory:DMABuf)` caps for frames like this one.
+pub fn caps(frame: &DmabufFrame, fps: u32) -> Option<gstreamer::Caps> {
+    let video_info = VideoInfo::builder(video_format(frame)?, frame.width, frame.height)
+        .fps(gstreamer::Fraction::new(fps as i32, 1))
+        .build()
+        .ok()?;
+    let mut caps = video_info.to_caps().ok()?;
+    caps.get_mut()
+        .unwrap()
+        .set_features(0, Some(gstreamer::CapsFeatures::new(["memory:DMABuf"])));
+    Some(caps)
+}
+
+unsafe extern "C" fn release_frame(frame: glib::ffi::gpointer) {
+    drop(Box::from_raw(frame as *mut DmabufFrame));
+}
+
+/// Wrap a dmabuf frame into a buffer without copying it. The frame, and with it the capture
+/// buffer, is held until GStreamer releases the memory.
+pub fn frame_to_buffer(
+    allocator: &DmaBufAllocator,
+    frame: DmabufFrame,
+) -> Result<gstreamer::Buffer, aError> {
+    let format = video_format(&frame)
+        .ok_or_else(|| aError::msg(format!("Unsupported dmabuf format {:#x}", frame.format)))?;
+    let offsets: Vec<usize> = frame.planes.iter().map(|p| p.offset as usize).collect();
+    let strides: Vec<i32> = frame.planes.iter().map(|p| p.stride as i32).collect();
+    let (width, height) = (frame.width, frame.height);
+
+    let fd = frame.fd().try_clone_to_owned()?;
+    let memory = unsafe { allocator.alloc(fd, frame.size())? };
+    unsafe {
+        gstreamer::ffi::gst_mini_object_set_qdata(
+            memory.as_mut_ptr() as *mut gstreamer::ffi::GstMiniObject,
+            glib::Quark::from_str("waystream-dmabuf-frame").into_glib(),
+            Box::into_raw(Box::new(frame)) as glib::ffi::gpointer,
+            Some(release_frame),
+        );
+    }
+
+    let mut buffer = gstreamer::Buffer::new();
+    {
+        let buffer = buffer.get_mut().unwrap();
+        buffer.append_memory(memory);
+        VideoMeta::add_full(
+            buffer,
+            VideoFrameFlags::empty(),
+            format,
+            width,
+            height,
+            &offsets,
+            &strides,
+        )?;
+    }
+    Ok(buffer)
+}
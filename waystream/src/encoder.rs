@@ -0,0 +1,274 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::Error as aError;
+use gstreamer::prelude::*;
+
+use crate::{make_element, sdp};
+
+/// Highest bitrate in kbit/s, x264enc's limit. In bit/s it still fits the i32 bitrates of the
+/// VP8, VP9 and rav1e encoders.
+pub const MAX_BITRATE: u32 = 2_048_000;
+
+/// Speed presets in the order of x264, from fastest to slowest. The other encoders get theirs
+/// mapped onto their own speed settings.
+pub const PRESETS: [&str; 10] = [
+    "ultrafast",
+    "superfast",
+    "veryfast",
+    "faster",
+    "fast",
+    "medium",
+    "slow",
+    "slower",
+    "veryslow",
+    "placebo",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    H264,
+    H265,
+    Vp8,
+    Vp9,
+    Av1,
+}
+
+impl Codec {
+    pub const ALL: [Codec; 5] = [Codec::H264, Codec::H265, Codec::Vp8, Codec::Vp9, Codec::Av1];
+
+    /// Name of the codec on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::H264 => "h264",
+            Codec::H265 => "h265",
+            Codec::Vp8 => "vp8",
+            Codec::Vp9 => "vp9",
+            Codec::Av1 => "av1",
+        }
+    }
+
+    /// Software encoders for the codec, in order of preference.
+    pub fn encoders(self) -> &'static [&'static str] {
+        match self {
+            Codec::H264 => &["x264enc"],
+            Codec::H265 => &["x265enc"],
+            Codec::Vp8 => &["vp8enc"],
+            Codec::Vp9 => &["vp9enc"],
+            Codec::Av1 => &["rav1enc", "svtav1enc"],
+        }
+    }
+
+    /// Parser that puts the encoded stream into a shape payloaders and muxers accept.
+    pub fn parser(self) -> Option<&'static str> {
+        match self {
+            Codec::H264 => Some("h264parse"),
+            Codec::H265 => Some("h265parse"),
+            Codec::Vp8 | Codec::Vp9 => None,
+            Codec::Av1 => Some("av1parse"),
+        }
+    }
+
+    pub fn payloader(self) -> &'static str {
+        match self {
+            Codec::H264 => "rtph264pay",
+            Codec::H265 => "rtph265pay",
+            Codec::Vp8 => "rtpvp8pay",
+            Codec::Vp9 => "rtpvp9pay",
+            Codec::Av1 => "rtpav1pay",
+        }
+    }
+
+    /// RTP encoding name of the codec for the SDP.
+    pub fn encoding_name(self) -> &'static str {
+        match self {
+            Codec::H264 => "H264",
+            Codec::H265 => "H265",
+            Codec::Vp8 => "VP8",
+            Codec::Vp9 => "VP9",
+            Codec::Av1 => "AV1",
+        }
+    }
+
+    /// Format specific parameters of the codec for the SDP.
+    pub fn format_parameters(self) -> Option<&'static str> {
+        match self {
+            Codec::H264 => Some("packetization-mode=1"),
+            _ => None,
+        }
+    }
+
+    /// Muxers that can hold the codec.
+    pub fn muxers(self) -> &'static [&'static str] {
+        match self {
+            Codec::H264 | Codec::H265 => &["mpegtsmux", "mp4mux", "matroskamux"],
+            Codec::Vp8 => &["webmmux", "matroskamux"],
+            Codec::Vp9 | Codec::Av1 => &["webmmux", "matroskamux", "mp4mux"],
+        }
+    }
+
+    /// Whether the codec is encoded with 10 bits per channel when asked to.
+    pub fn supports_10bit(self) -> bool {
+        matches!(self, Codec::H265 | Codec::Av1)
+    }
+
+    /// First encoder for the codec that is installed.
+    pub fn available_encoder(self) -> Option<&'static str> {
+        self.encoders()
+            .iter()
+            .copied()
+            .find(|encoder| gstreamer::ElementFactory::find(encoder).is_some())
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Codec {
+    type Err = aError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Codec::ALL
+            .into_iter()
+            .find(|codec| codec.name() == s)
+            .ok_or_else(|| aError::msg(format!("Unknown codec {s}")))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EncoderOptions {
+    pub codec: Codec,
+    /// Target bitrate in kbit/s.
+    pub bitrate: u32,
+    /// Maximum number of frames between keyframes.
+    pub keyframe_interval: u32,
+    /// One of PRESETS.
+    pub preset: String,
+    /// Bits per channel of the encoded video, 8 or 10.
+    pub bit_depth: u32,
+}
+
+/// Log which encoders, payloaders and muxers are installed for every codec.
+pub fn probe() {
+    let available = |factory: &str| gstreamer::ElementFactory::find(factory).is_some();
+    for codec in Codec::ALL {
+        match codec.available_encoder() {
+            Some(encoder) => {
+                let muxers: Vec<&str> = codec
+                    .muxers()
+                    .iter()
+                    .copied()
+                    .filter(|muxer| available(muxer))
+                    .collect();
+                log::info!(
+                    "{codec}: encoder {encoder}, rtp {}, muxers [{}]",
+                    if available(codec.payloader()) {
+                        codec.payloader()
+                    } else {
+                        "unavailable"
+                    },
+                    muxers.join(", ")
+                );
+            }
+            None => log::info!(
+                "{codec}: unavailable, install one of {}",
+                codec.encoders().join(", ")
+            ),
+        }
+    }
+}
+
+/// Create the elements that turn raw video into an encoded, parsed stream of the codec.
+pub fn make_encoder(opts: &EncoderOptions) -> Result<Vec<gstreamer::Element>, aError> {
+    let codec = opts.codec;
+    let factory = codec.available_encoder().ok_or_else(|| {
+        aError::msg(format!(
+            "No encoder for {codec} available, install one of {}",
+            codec.encoders().join(", ")
+        ))
+    })?;
+
+    if opts.bit_depth == 10 && !codec.supports_10bit() {
+        return Err(aError::msg(format!(
+            "{codec} can't be encoded with 10 bits, use h265 or av1"
+        )));
+    }
+
+    // Convert to 4:2:0 which every decoder handles, x265 picks main10 and the AV1 encoders their
+    // high bit depth mode for 10 bit input
+    let format = if opts.bit_depth == 10 {
+        "I420_10LE"
+    } else {
+        "I420"
+    };
+    let encoder_caps = gstreamer::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gstreamer::Caps::builder("video/x-raw")
+                .field("format", format)
+                .build(),
+        )
+        .build()?;
+
+    let speed = PRESETS
+        .iter()
+        .position(|preset| *preset == opts.preset)
+        .unwrap_or(0);
+    let encoder = make_element(factory)?;
+    match factory {
+        "x264enc" | "x265enc" => {
+            encoder.set_property("bitrate", opts.bitrate);
+            if factory == "x264enc" {
+                encoder.set_property("key-int-max", opts.keyframe_interval);
+            } else {
+                encoder.set_property("key-int-max", opts.keyframe_interval as i32);
+            }
+            encoder.set_property_from_str("tune", "zerolatency");
+            encoder.set_property_from_str("speed-preset", &opts.preset);
+        }
+        "vp8enc" | "vp9enc" => {
+            let cpu_used = if factory == "vp8enc" {
+                [16, 12, 10, 8, 6, 5, 4, 2, 1, 0][speed]
+            } else {
+                [8, 8, 7, 6, 5, 4, 3, 2, 1, 0][speed]
+            };
+            encoder.set_property("target-bitrate", (opts.bitrate * 1000) as i32);
+            encoder.set_property("keyframe-max-dist", opts.keyframe_interval as i32);
+            encoder.set_property("cpu-used", cpu_used);
+            encoder.set_property("deadline", 1i64);
+            encoder.set_property("lag-in-frames", 0i32);
+            encoder.set_property_from_str("end-usage", "cbr");
+        }
+        "rav1enc" => {
+            encoder.set_property("bitrate", (opts.bitrate * 1000) as i32);
+            encoder.set_property("max-key-frame-interval", u64::from(opts.keyframe_interval));
+            encoder.set_property("speed-preset", 10 - speed as u32);
+            encoder.set_property("low-latency", true);
+        }
+        "svtav1enc" => {
+            encoder.set_property("target-bitrate", opts.bitrate);
+            encoder.set_property("intra-period-length", opts.keyframe_interval as i32);
+            encoder.set_property("preset", 13 - speed as u32);
+        }
+        _ => unreachable!("Unhandled encoder {factory}"),
+    }
+
+    let mut elements = vec![encoder_caps, encoder];
+    if let Some(parser) = codec.parser() {
+        elements.push(make_element(parser)?);
+    }
+    Ok(elements)
+}
+
+/// Create the RTP payloader for the codec.
+pub fn make_payloader(codec: Codec) -> Result<gstreamer::Element, aError> {
+    let payloader = make_element(codec.payloader())?;
+    payloader.set_property("pt", sdp::PAYLOAD_TYPE);
+    if matches!(codec, Codec::H264 | Codec::H265) {
+        // Send the parameter sets with every keyframe so receivers can join at any time
+        payloader.set_property("config-interval", -1i32);
+    }
+    Ok(payloader)
+}
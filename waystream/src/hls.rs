@@ -0,0 +1,393 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Error as aError;
+use gstreamer::prelude::*;
+
+use crate::MissingElement;
+
+#[derive(Debug, Clone)]
+pub struct HlsOptions {
+    /// Directory the playlist and segments are written to.
+    pub dir: PathBuf,
+    /// Target duration of a segment in seconds.
+    pub segment_duration: u32,
+    /// Number of segments in the playlist.
+    pub playlist_length: u32,
+    /// Segment file name pattern, relative to `dir`, default depends on the segment format.
+    pub location: Option<String>,
+    /// Write fragmented MP4 (CMAF) segments instead of MPEG-TS.
+    pub fmp4: bool,
+    /// Duration of the parts of low latency HLS in milliseconds, none for regular HLS. Low
+    /// latency HLS is always written as fragmented MP4.
+    pub part_duration: Option<u32>,
+}
+
+/// Create the elements writing the HLS playlist and segments. They take parsed H.264, hlssink2
+/// takes the audio on its `audio` pad.
+pub fn make_sink(opts: &HlsOptions) -> Result<Vec<gstreamer::Element>, aError> {
+    fs::create_dir_all(&opts.dir)?;
+    if let Some(part_duration) = opts.part_duration {
+        return make_low_latency_sink(opts, part_duration);
+    }
+
+    let (factory, default_location) = if opts.fmp4 {
+        ("hlscmafsink", "segment%05d.m4s")
+    } else {
+        ("hlssink2", "segment%05d.ts")
+    };
+    let location = opts
+        .dir
+        .join(opts.location.as_deref().unwrap_or(default_location));
+    let playlist_location = opts.dir.join("playlist.m3u8");
+    // Keep a few segments beyond the playlist around for clients that are still downloading them
+    let max_files = opts.playlist_length + 2;
+
+    let sink = gstreamer::ElementFactory::make(factory)
+        .name("hlssink")
+        .property("location", location.to_string_lossy().as_ref())
+        .property(
+            "playlist-location",
+            playlist_location.to_string_lossy().as_ref(),
+        )
+        .property("target-duration", opts.segment_duration)
+        .property("playlist-length", opts.playlist_length)
+        .build()
+        .map_err(|_| MissingElement(factory))?;
+
+    if opts.fmp4 {
+        let init_location = opts.dir.join("init%05d.mp4");
+        sink.set_property("init-location", init_location.to_string_lossy().as_ref());
+        sink.set_property("max-num-segment-files", max_files);
+    } else {
+        sink.set_property("max-files", max_files);
+    }
+
+    log::info!("Writing HLS playlist to {}", playlist_location.display());
+    Ok(vec![sink])
+}
+
+/// Create cmafmux cutting the stream into segments and their parts, and the appsink they are
+/// written to the directory from along with the playlist. No HLS sink writes parts, so the
+/// playlist is maintained here.
+fn make_low_latency_sink(
+    opts: &HlsOptions,
+    part_duration: u32,
+) -> Result<Vec<gstreamer::Element>, aError> {
+    let part_duration = gstreamer::ClockTime::from_mseconds(u64::from(part_duration));
+    // Segments start at the first keyframe after the target duration, every chunk is a part
+    let cmafmux = gstreamer::ElementFactory::make("cmafmux")
+        .property(
+            "fragment-duration",
+            gstreamer::ClockTime::from_seconds(u64::from(opts.segment_duration)),
+        )
+        .property("chunk-duration", part_duration)
+        .build()
+        .map_err(|_| MissingElement("cmafmux"))?;
+    let appsink = gstreamer_app::AppSink::builder()
+        .name("hlssink")
+        .buffer_list(true)
+        .sync(false)
+        .build();
+
+    let playlist = Mutex::new(LowLatencyPlaylist::new(opts, part_duration));
+    appsink.set_callbacks(
+        gstreamer_app::AppSinkCallbacks::builder()
+            .new_sample(move |appsink| {
+                let sample = appsink
+                    .pull_sample()
+                    .map_err(|_| gstreamer::FlowError::Eos)?;
+                let buffers: Vec<&gstreamer::BufferRef> = match sample.buffer_list() {
+                    Some(buffer_list) => buffer_list.iter().collect(),
+                    None => sample.buffer().into_iter().collect(),
+                };
+                if let Err(e) = playlist.lock().unwrap().push(&buffers) {
+                    log::error!("Failed to write HLS part: {e}");
+                    return Err(gstreamer::FlowError::Error);
+                }
+                Ok(gstreamer::FlowSuccess::Ok)
+            })
+            .build(),
+    );
+
+    log::info!(
+        "Writing low latency HLS playlist to {}",
+        opts.dir.join(PLAYLIST).display()
+    );
+    Ok(vec![cmafmux, appsink.upcast()])
+}
+
+const PLAYLIST: &str = "playlist.m3u8";
+const INIT_SEGMENT: &str = "init.mp4";
+
+/// A segment of a low latency stream, written part by part while it is the newest one.
+struct Segment {
+    sequence: u64,
+    /// Duration of every part in seconds.
+    parts: Vec<f64>,
+    data: Vec<u8>,
+}
+
+impl Segment {
+    fn name(&self) -> String {
+        format!("segment{:05}.m4s", self.sequence)
+    }
+
+    fn part_name(&self, part: usize) -> String {
+        format!("segment{:05}.{part}.m4s", self.sequence)
+    }
+
+    fn duration(&self) -> f64 {
+        self.parts.iter().sum()
+    }
+}
+
+/// The segments and parts of a low latency HLS stream, and the playlist listing them.
+struct LowLatencyPlaylist {
+    dir: PathBuf,
+    segment_duration: u32,
+    part_duration: f64,
+    playlist_length: usize,
+    /// Finished segments, a few more than the playlist lists for clients still loading them.
+    segments: VecDeque<Segment>,
+    /// The segment whose parts are being written.
+    current: Option<Segment>,
+    next_sequence: u64,
+}
+
+impl LowLatencyPlaylist {
+    fn new(opts: &HlsOptions, part_duration: gstreamer::ClockTime) -> Self {
+        Self {
+            dir: opts.dir.clone(),
+            segment_duration: opts.segment_duration,
+            part_duration: part_duration.nseconds() as f64 / 1e9,
+            playlist_length: opts.playlist_length as usize,
+            segments: VecDeque::new(),
+            current: None,
+            next_sequence: 0,
+        }
+    }
+
+    /// Write the output of cmafmux: the init segment when it comes with headers, and a part that
+    /// starts a new segment unless it is a delta unit.
+    fn push(&mut self, buffers: &[&gstreamer::BufferRef]) -> io::Result<()> {
+        let headers = buffers
+            .iter()
+            .take_while(|buffer| buffer.flags().contains(gstreamer::BufferFlags::HEADER))
+            .count();
+        if headers > 0 {
+            write_atomically(&self.dir.join(INIT_SEGMENT), &concat(&buffers[..headers])?)?;
+        }
+        let Some(first) = buffers.get(headers) else {
+            return Ok(());
+        };
+
+        let independent = !first.flags().contains(gstreamer::BufferFlags::DELTA_UNIT);
+        if independent || self.current.is_none() {
+            self.finish_segment()?;
+            self.current = Some(Segment {
+                sequence: self.next_sequence,
+                parts: Vec::new(),
+                data: Vec::new(),
+            });
+            self.next_sequence += 1;
+        }
+        let segment = self.current.as_mut().unwrap();
+
+        let data = concat(&buffers[headers..])?;
+        write_atomically(
+            &self.dir.join(segment.part_name(segment.parts.len())),
+            &data,
+        )?;
+        segment.parts.push(
+            first
+                .duration()
+                .map(|duration| duration.nseconds() as f64 / 1e9)
+                .unwrap_or(self.part_duration),
+        );
+        segment.data.extend_from_slice(&data);
+        self.write_playlist()
+    }
+
+    /// Write the current segment as a whole and drop the oldest one out of the directory.
+    fn finish_segment(&mut self) -> io::Result<()> {
+        let Some(segment) = self.current.take() else {
+            return Ok(());
+        };
+        write_atomically(&self.dir.join(segment.name()), &segment.data)?;
+        self.segments.push_back(Segment {
+            data: Vec::new(),
+            ..segment
+        });
+
+        while self.segments.len() > self.playlist_length + 2 {
+            let segment = self.segments.pop_front().unwrap();
+            let _ = fs::remove_file(self.dir.join(segment.name()));
+            for part in 0..segment.parts.len() {
+                let _ = fs::remove_file(self.dir.join(segment.part_name(part)));
+            }
+        }
+        Ok(())
+    }
+
+    fn write_playlist(&self) -> io::Result<()> {
+        let listed: Vec<&Segment> = self
+            .segments
+            .iter()
+            .skip(self.segments.len().saturating_sub(self.playlist_length))
+            .collect();
+        let target_duration = listed
+            .iter()
+            .map(|segment| segment.duration().ceil() as u32)
+            .fold(self.segment_duration, u32::max);
+        let first_sequence = listed
+            .first()
+            .copied()
+            .or(self.current.as_ref())
+            .map_or(0, |segment| segment.sequence);
+
+        let mut playlist = String::new();
+        let _ = write!(
+            playlist,
+            "#EXTM3U\n\
+             #EXT-X-VERSION:6\n\
+             #EXT-X-TARGETDURATION:{target_duration}\n\
+             #EXT-X-PART-INF:PART-TARGET={part:.3}\n\
+             #EXT-X-SERVER-CONTROL:PART-HOLD-BACK={hold_back:.3}\n\
+             #EXT-X-MEDIA-SEQUENCE:{first_sequence}\n\
+             #EXT-X-INDEPENDENT-SEGMENTS\n\
+             #EXT-X-MAP:URI=\"{INIT_SEGMENT}\"\n",
+            part = self.part_duration,
+            hold_back = 3.0 * self.part_duration,
+        );
+
+        // Parts are only listed for the last three target durations, older segments are loaded
+        // whole
+        let mut age: f64 = listed.iter().map(|segment| segment.duration()).sum();
+        for segment in listed {
+            if age <= 3.0 * f64::from(target_duration) {
+                write_parts(&mut playlist, segment);
+            }
+            age -= segment.duration();
+            let _ = write!(
+                playlist,
+                "#EXTINF:{:.3},\n{}\n",
+                segment.duration(),
+                segment.name()
+            );
+        }
+        if let Some(segment) = &self.current {
+            write_parts(&mut playlist, segment);
+            let _ = writeln!(
+                playlist,
+                "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"{}\"",
+                segment.part_name(segment.parts.len())
+            );
+        }
+
+        write_atomically(&self.dir.join(PLAYLIST), playlist.as_bytes())
+    }
+}
+
+fn write_parts(playlist: &mut String, segment: &Segment) {
+    for (part, duration) in segment.parts.iter().enumerate() {
+        let _ = writeln!(
+            playlist,
+            "#EXT-X-PART:DURATION={duration:.3},URI=\"{}\"{}",
+            segment.part_name(part),
+            if part == 0 { ",INDEPENDENT=YES" } else { "" }
+        );
+    }
+}
+
+fn concat(buffers: &[&gstreamer::BufferRef]) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for buffer in buffers {
+        let map = buffer
+            .map_readable()
+            .map_err(|_| io::Error::other("Unreadable buffer"))?;
+        data.extend_from_slice(&map);
+    }
+    Ok(data)
+}
+
+/// Clients poll the files while they are written, they only ever see complete ones.
+fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn buffer(data: &[u8], flags: gstreamer::BufferFlags, millis: u64) -> gstreamer::Buffer {
+        let mut buffer = gstreamer::Buffer::from_slice(data.to_vec());
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_flags(flags);
+            buffer.set_duration(gstreamer::ClockTime::from_mseconds(millis));
+        }
+        buffer
+    }
+
+    #[test]
+    fn writes_segments_and_parts() {
+        gstreamer::init().unwrap();
+        let dir = env::temp_dir().join(format!("waystream-ll-hls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let opts = HlsOptions {
+            dir: dir.clone(),
+            segment_duration: 1,
+            playlist_length: 2,
+            location: None,
+            fmp4: true,
+            part_duration: Some(500),
+        };
+        let mut playlist = LowLatencyPlaylist::new(&opts, gstreamer::ClockTime::from_mseconds(500));
+
+        let header = buffer(b"init", gstreamer::BufferFlags::HEADER, 0);
+        let delta = gstreamer::BufferFlags::DELTA_UNIT;
+        for segment in 0..4u8 {
+            let first = buffer(&[segment, 0], gstreamer::BufferFlags::empty(), 500);
+            if segment == 0 {
+                playlist.push(&[header.as_ref(), first.as_ref()]).unwrap();
+            } else {
+                playlist.push(&[first.as_ref()]).unwrap();
+            }
+            playlist
+                .push(&[buffer(&[segment, 1], delta, 500).as_ref()])
+                .unwrap();
+        }
+
+        assert_eq!(fs::read(dir.join(INIT_SEGMENT)).unwrap(), b"init");
+        assert_eq!(
+            fs::read(dir.join("segment00002.m4s")).unwrap(),
+            [2, 0, 2, 1]
+        );
+        assert_eq!(fs::read(dir.join("segment00003.1.m4s")).unwrap(), [3, 1]);
+
+        let text = fs::read_to_string(dir.join(PLAYLIST)).unwrap();
+        assert!(text.contains("#EXT-X-MEDIA-SEQUENCE:1\n"), "{text}");
+        assert!(!text.contains("segment00000"), "{text}");
+        assert!(
+            text.contains("#EXTINF:1.000,\nsegment00002.m4s\n"),
+            "{text}"
+        );
+        assert!(text.contains(
+            "#EXT-X-PART:DURATION=0.500,URI=\"segment00003.0.m4s\",INDEPENDENT=YES\n\
+             #EXT-X-PART:DURATION=0.500,URI=\"segment00003.1.m4s\"\n\
+             #EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"segment00003.2.m4s\"\n"
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
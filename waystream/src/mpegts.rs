@@ -0,0 +1,46 @@
+use anyhow::Error as aError;
+use gstreamer::prelude::*;
+
+use crate::{audio::AudioCodec, encoder::Codec, sdp, MissingElement};
+
+/// Transport stream packets in a datagram, 7 × 188 bytes fit into the MTU of any network.
+pub const PACKETS_PER_DATAGRAM: i32 = 7;
+/// RTP encoding name of a transport stream.
+pub const ENCODING_NAME: &str = "MP2T";
+
+/// Create the mpegtsmux for the parsed stream of the codec, `parser` is the parser that ends the
+/// encoder's elements. The audio goes into a `sink_%d` pad of the mux.
+pub fn make_mux(
+    codec: Codec,
+    audio_codec: Option<AudioCodec>,
+    parser: &gstreamer::Element,
+) -> Result<gstreamer::Element, aError> {
+    if !codec.muxers().contains(&"mpegtsmux") {
+        return Err(aError::msg(format!(
+            "{codec} can't be carried in MPEG-TS, use h264 or h265"
+        )));
+    }
+    if let Some(audio_codec) =
+        audio_codec.filter(|audio_codec| !audio_codec.muxers().contains(&"mpegtsmux"))
+    {
+        return Err(aError::msg(format!(
+            "{audio_codec} can't be carried in MPEG-TS, pick another --audio-codec"
+        )));
+    }
+
+    // Receivers tune in at any time, they need the parameter sets with every keyframe
+    parser.set_property("config-interval", -1i32);
+    // mpegtsmux repeats the PAT and PMT every 100 ms, so receivers also find the programs late
+    gstreamer::ElementFactory::make("mpegtsmux")
+        .property("alignment", PACKETS_PER_DATAGRAM)
+        .build()
+        .map_err(|_| MissingElement("mpegtsmux").into())
+}
+
+/// Create the RTP payloader for the transport stream.
+pub fn make_payloader() -> Result<gstreamer::Element, aError> {
+    gstreamer::ElementFactory::make("rtpmp2tpay")
+        .property("pt", sdp::PAYLOAD_TYPE)
+        .build()
+        .map_err(|_| MissingElement("rtpmp2tpay").into())
+}
@@ -0,0 +1,165 @@
+use std::time::Duration;
+
+use gstreamer::{glib, prelude::*};
+
+/// Hands frames to the appsrc, either at a constant rate or whenever the screen changed.
+///
+/// At a constant rate frames go into slots of one frame duration on the pipeline's running time,
+/// slots missed while a capture took too long are filled with the previous frame.
+///
+/// At a variable rate frames are timestamped with the time the compositor presented them and
+/// come no faster than the target rate. Without new content the previous frame is repeated after
+/// the keep-alive interval.
+pub struct FramePacer {
+    frame_duration: gstreamer::ClockTime,
+    /// Set for variable frame rate.
+    keepalive: Option<Duration>,
+    next_slot: u64,
+    last_pts: Option<gstreamer::ClockTime>,
+    last_buffer: Option<gstreamer::Buffer>,
+}
+
+/// More missed slots than this are skipped instead of filled, e.g. after the pipeline was paused.
+const MAX_DUPLICATES: u64 = 60;
+
+impl FramePacer {
+    pub fn new(fps: u32) -> Self {
+        FramePacer {
+            frame_duration: gstreamer::ClockTime::from_nseconds(
+                gstreamer::ClockTime::SECOND.nseconds() / u64::from(fps),
+            ),
+            keepalive: None,
+            next_slot: 0,
+            last_pts: None,
+            last_buffer: None,
+        }
+    }
+
+    /// Pace a variable frame rate stream of at most `fps` frames per second.
+    pub fn variable(fps: u32, keepalive: Duration) -> Self {
+        FramePacer {
+            keepalive: Some(keepalive),
+            ..FramePacer::new(fps)
+        }
+    }
+
+    pub fn frame_duration(&self) -> gstreamer::ClockTime {
+        self.frame_duration
+    }
+
+    /// How long to wait for new content before repeating the last frame.
+    pub fn damage_timeout(&self) -> Duration {
+        self.keepalive.unwrap_or(self.frame_duration.into())
+    }
+
+    /// Block until the next frame is due. Before the pipeline runs there is no clock, then this
+    /// returns right away.
+    pub fn wait(&self, appsrc: &gstreamer_app::AppSrc) {
+        let next_frame = match (self.keepalive, self.last_pts) {
+            (None, _) => self.frame_duration * self.next_slot,
+            (Some(_), Some(last_pts)) => last_pts + self.frame_duration,
+            (Some(_), None) => return,
+        };
+        if let (Some(clock), Some(base_time)) = (appsrc.clock(), appsrc.base_time()) {
+            let _ = clock.new_single_shot_id(base_time + next_frame).wait();
+        }
+    }
+
+    /// Current slot on the running time of the appsrc.
+    fn current_slot(&self, appsrc: &gstreamer_app::AppSrc) -> u64 {
+        appsrc
+            .current_running_time()
+            .map(|running_time| running_time.nseconds() / self.frame_duration.nseconds())
+            .unwrap_or(self.next_slot)
+            .max(self.next_slot)
+    }
+
+    /// Timestamp a frame and push it. Without a frame, because nothing changed on screen, the
+    /// previous one is repeated.
+    pub fn push(
+        &mut self,
+        appsrc: &gstreamer_app::AppSrc,
+        buffer: Option<gstreamer::Buffer>,
+        presentation_time: Option<Duration>,
+    ) -> Result<gstreamer::FlowSuccess, gstreamer::FlowError> {
+        if self.keepalive.is_some() {
+            return self.push_variable(appsrc, buffer, presentation_time);
+        }
+
+        let slot = self.current_slot(appsrc);
+
+        if let Some(last_buffer) = &self.last_buffer {
+            let missed = slot - self.next_slot;
+            if missed > MAX_DUPLICATES {
+                log::debug!("Skipping {missed} frames");
+            } else if missed > 0 {
+                log::trace!("Capture fell behind, repeating the last frame {missed} times");
+                for missed_slot in self.next_slot..slot {
+                    let pts = self.frame_duration * missed_slot;
+                    appsrc.push_buffer(self.timestamped(last_buffer, pts, true))?;
+                }
+            }
+        }
+
+        let Some(buffer) = buffer.or_else(|| self.last_buffer.clone()) else {
+            return Ok(gstreamer::FlowSuccess::Ok);
+        };
+        let buffer = self.timestamped(&buffer, self.frame_duration * slot, true);
+        self.last_buffer = Some(buffer.clone());
+        self.next_slot = slot + 1;
+        appsrc.push_buffer(buffer)
+    }
+
+    fn push_variable(
+        &mut self,
+        appsrc: &gstreamer_app::AppSrc,
+        buffer: Option<gstreamer::Buffer>,
+        presentation_time: Option<Duration>,
+    ) -> Result<gstreamer::FlowSuccess, gstreamer::FlowError> {
+        // Presentation times are on CLOCK_MONOTONIC, move them onto the running time by how long
+        // ago they were
+        let running_time = appsrc.current_running_time();
+        let pts = match (running_time, presentation_time, buffer.is_some()) {
+            (Some(running_time), Some(presentation_time), true) => {
+                let now = Duration::from_micros(glib::monotonic_time() as u64);
+                let age = gstreamer::ClockTime::try_from(now.saturating_sub(presentation_time))
+                    .unwrap_or(gstreamer::ClockTime::ZERO);
+                running_time.saturating_sub(age)
+            }
+            (Some(running_time), _, _) => running_time,
+            (None, _, _) => self
+                .last_pts
+                .map(|last_pts| last_pts + self.frame_duration)
+                .unwrap_or(gstreamer::ClockTime::ZERO),
+        };
+        // Timestamps have to keep increasing even if the compositor's don't
+        let pts = match self.last_pts {
+            Some(last_pts) if pts <= last_pts => last_pts + gstreamer::ClockTime::from_nseconds(1),
+            _ => pts,
+        };
+
+        let Some(buffer) = buffer.or_else(|| self.last_buffer.clone()) else {
+            return Ok(gstreamer::FlowSuccess::Ok);
+        };
+        let buffer = self.timestamped(&buffer, pts, false);
+        self.last_buffer = Some(buffer.clone());
+        self.last_pts = Some(pts);
+        appsrc.push_buffer(buffer)
+    }
+
+    fn timestamped(
+        &self,
+        buffer: &gstreamer::Buffer,
+        pts: gstreamer::ClockTime,
+        with_duration: bool,
+    ) -> gstreamer::Buffer {
+        let mut buffer = buffer.copy();
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_pts(pts);
+            // The length of a frame is unknown until the next one arrives at a variable rate
+            buffer.set_duration(with_duration.then_some(self.frame_duration));
+        }
+        buffer
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Error as aError;
+use gstreamer::{glib, prelude::*};
+
+use crate::{audio::AudioCodec, encoder::Codec, MissingElement};
+
+#[derive(Debug, Clone)]
+pub struct RecordOptions {
+    /// File the recording is written to, its extension picks the container. For segmented
+    /// recordings `{sequence}` and `{timestamp}` in the name are replaced for every segment.
+    pub path: PathBuf,
+    /// Start a new segment after this many seconds.
+    pub segment_duration: Option<u64>,
+    /// Start a new segment after this many bytes.
+    pub segment_size: Option<u64>,
+    /// Delete the oldest segments to keep at most this many on disk.
+    pub max_segments: Option<u32>,
+}
+
+impl RecordOptions {
+    pub fn segmented(&self) -> bool {
+        self.segment_duration.is_some() || self.segment_size.is_some()
+    }
+}
+
+/// Parse a size in bytes with an optional K, M or G suffix.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((index, 'k' | 'K')) => (&s[..index], 1 << 10),
+        Some((index, 'm' | 'M')) => (&s[..index], 1 << 20),
+        Some((index, 'g' | 'G')) => (&s[..index], 1 << 30),
+        _ => (s, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .filter(|size| *size > 0)
+        .ok_or_else(|| {
+            format!("Invalid size {s}, expected bytes with an optional K, M or G suffix")
+        })
+}
+
+/// Fill in the placeholders of a segment file name. Templates without any get the sequence
+/// number appended to the file stem.
+fn segment_location(template: &Path, sequence: u32) -> PathBuf {
+    let mut template = template.to_string_lossy().into_owned();
+    if !template.contains("{sequence}") && !template.contains("{timestamp}") {
+        let stem = template
+            .rfind('.')
+            .filter(|index| !template[*index..].contains('/'))
+            .unwrap_or(template.len());
+        template.insert_str(stem, "-{sequence}");
+    }
+
+    let timestamp = glib::DateTime::now_local()
+        .and_then(|now| now.format("%Y%m%d-%H%M%S"))
+        .map(|timestamp| timestamp.to_string())
+        .unwrap_or_default();
+    PathBuf::from(
+        template
+            .replace("{sequence}", &format!("{sequence:05}"))
+            .replace("{timestamp}", &timestamp),
+    )
+}
+
+/// Pick the muxer for a recording from the file extension.
+pub fn muxer_for(
+    path: &Path,
+    codec: Codec,
+    audio_codec: Option<AudioCodec>,
+) -> Result<&'static str, aError> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    let muxer = match extension.as_deref() {
+        Some("mp4") => "mp4mux",
+        Some("mkv") => "matroskamux",
+        Some("webm") => "webmmux",
+        _ => {
+            return Err(aError::msg(format!(
+                "Can't record to {}, use a .mp4, .mkv or .webm file",
+                path.display()
+            )))
+        }
+    };
+    if !codec.muxers().contains(&muxer) {
+        return Err(aError::msg(format!(
+            "{codec} can't be stored in {muxer}, pick another --codec or file extension"
+        )));
+    }
+    if let Some(audio_codec) =
+        audio_codec.filter(|audio_codec| !audio_codec.muxers().contains(&muxer))
+    {
+        return Err(aError::msg(format!(
+            "{audio_codec} can't be stored in {muxer}, pick another --audio-codec or file extension"
+        )));
+    }
+    Ok(muxer)
+}
+
+/// Create the muxer and file sink of a recording. They take the parsed stream of the codec, the
+/// audio goes into an `audio_%u` pad of the first element.
+pub fn make_sink(
+    opts: &RecordOptions,
+    codec: Codec,
+    audio_codec: Option<AudioCodec>,
+) -> Result<Vec<gstreamer::Element>, aError> {
+    let muxer = muxer_for(&opts.path, codec, audio_codec)?;
+    if opts.segmented() {
+        return make_segmented_sink(opts, muxer);
+    }
+    let path = opts.path.to_string_lossy();
+    if path.contains("{sequence}") || path.contains("{timestamp}") {
+        return Err(aError::msg(format!(
+            "{path} has segment placeholders, they need --segment-duration or --segment-size"
+        )));
+    }
+
+    let mux = gstreamer::ElementFactory::make(muxer)
+        .build()
+        .map_err(|_| MissingElement(muxer))?;
+    let filesink = gstreamer::ElementFactory::make("filesink")
+        .property("location", path.as_ref())
+        .build()?;
+
+    log::info!("Recording to {}", opts.path.display());
+    Ok(vec![mux, filesink])
+}
+
+/// Create a splitmuxsink that rotates the recording by time and size.
+fn make_segmented_sink(
+    opts: &RecordOptions,
+    muxer: &'static str,
+) -> Result<Vec<gstreamer::Element>, aError> {
+    if gstreamer::ElementFactory::find(muxer).is_none() {
+        return Err(MissingElement(muxer).into());
+    }
+    let splitmuxsink = gstreamer::ElementFactory::make("splitmuxsink")
+        .property("muxer-factory", muxer)
+        .property(
+            "max-size-time",
+            opts.segment_duration.unwrap_or(0) * gstreamer::ClockTime::SECOND.nseconds(),
+        )
+        .property("max-size-bytes", opts.segment_size.unwrap_or(0))
+        .build()
+        .map_err(|_| MissingElement("splitmuxsink"))?;
+
+    // splitmuxsink's own max-files reuses file names, which doesn't work with timestamps in
+    // them, so old segments are deleted here
+    let template = opts.path.clone();
+    let max_segments = opts.max_segments.map(|max_segments| max_segments as usize);
+    let segments = Mutex::new(VecDeque::new());
+    splitmuxsink.connect("format-location", false, move |args| {
+        let sequence = args[1].get::<u32>().unwrap();
+        let location = segment_location(&template, sequence);
+        log::info!("Recording segment {}", location.display());
+
+        let mut segments = segments.lock().unwrap();
+        segments.push_back(location.clone());
+        while max_segments.is_some_and(|max_segments| segments.len() > max_segments) {
+            let old_location = segments.pop_front().unwrap();
+            if let Err(e) = fs::remove_file(&old_location) {
+                log::warn!("Failed to delete segment {}: {e}", old_location.display());
+            }
+        }
+        Some(location.to_string_lossy().to_value())
+    });
+
+    log::info!("Recording segments to {}", opts.path.display());
+    Ok(vec![splitmuxsink])
+}
@@ -0,0 +1,316 @@
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use anyhow::Error as aError;
+use gstreamer::{glib, prelude::*};
+use gstreamer_rtsp_server::prelude::*;
+
+use crate::{address, audio::AudioCodec, encoder::Codec, post_application_message, sdp};
+
+/// Posted on the pipeline bus when the media of a client is configured. That happens on its
+/// DESCRIBE or first SETUP, before it plays, and also for clients that never do.
+pub const CLIENT_CONNECTED: &str = "waystream-rtsp-client-connected";
+/// Posted on the pipeline bus when the media of a client is unprepared, after its session has
+/// been torn down.
+pub const CLIENT_DISCONNECTED: &str = "waystream-rtsp-client-disconnected";
+
+/// Where the RTSP server listens, parsed from `address:port/path`.
+#[derive(Debug, Clone)]
+pub struct RtspOptions {
+    pub address: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl FromStr for RtspOptions {
+    type Err = aError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            aError::msg(format!(
+                "Invalid RTSP address {s}, expected address:port/path"
+            ))
+        };
+
+        let (bind, path) = match s.find('/') {
+            Some(index) => s.split_at(index),
+            None => (s, "/"),
+        };
+        let (address, port) = address::parse(bind)
+            .filter(|(address, _)| !address.is_empty())
+            .ok_or_else(invalid)?;
+
+        Ok(RtspOptions {
+            address: address.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// RTSP server handing the encoded stream out to its clients. Every client gets its own media
+/// that is fed from the appsink at the end of the RTSP branch of the pipeline.
+pub struct Server {
+    _server: gstreamer_rtsp_server::RTSPServer,
+    main_loop: glib::MainLoop,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.main_loop.quit();
+    }
+}
+
+type Clients = Arc<Mutex<Vec<gstreamer_app::AppSrc>>>;
+
+/// Create the appsink the RTSP branch of the pipeline ends in.
+pub fn make_sink() -> gstreamer_app::AppSink {
+    gstreamer_app::AppSink::builder().name("rtspsink").build()
+}
+
+/// Create the appsink the audio for RTSP clients ends in.
+pub fn make_audio_sink() -> gstreamer_app::AppSink {
+    gstreamer_app::AppSink::builder()
+        .name("rtspaudiosink")
+        .build()
+}
+
+/// Create the valve at the start of an RTSP branch, closed until the first client connects so
+/// nothing is encoded for nobody.
+pub fn make_valve() -> Result<gstreamer::Element, aError> {
+    let valve = gstreamer::ElementFactory::make("valve")
+        .property("drop", true)
+        .build()?;
+
+    // A closed valve drops EOS along with the buffers, so without clients the sinks of the branch
+    // would never finish and the pipeline never posts EOS. Pass it on around the valve.
+    let src_pad = valve.static_pad("src").unwrap().downgrade();
+    valve.static_pad("sink").unwrap().add_probe(
+        gstreamer::PadProbeType::EVENT_DOWNSTREAM,
+        move |pad, info| {
+            let Some(gstreamer::PadProbeData::Event(event)) = &info.data else {
+                return gstreamer::PadProbeReturn::Ok;
+            };
+            let closed = pad
+                .parent_element()
+                .is_some_and(|valve| valve.property::<bool>("drop"));
+            if event.type_() != gstreamer::EventType::Eos || !closed {
+                return gstreamer::PadProbeReturn::Ok;
+            }
+            if let Some(src_pad) = src_pad.upgrade() {
+                src_pad.push_event(event.clone());
+            }
+            gstreamer::PadProbeReturn::Drop
+        },
+    );
+    Ok(valve)
+}
+
+/// Hand every sample of the appsink to the appsrc of each client.
+fn forward_samples(appsink: &gstreamer_app::AppSink, clients: Clients) {
+    appsink.set_callbacks(
+        gstreamer_app::AppSinkCallbacks::builder()
+            .new_sample(move |appsink| {
+                let sample = appsink
+                    .pull_sample()
+                    .map_err(|_| gstreamer::FlowError::Eos)?;
+                let Some(buffer) = sample.buffer() else {
+                    return Ok(gstreamer::FlowSuccess::Ok);
+                };
+
+                for appsrc in clients.lock().unwrap().iter() {
+                    if appsrc.caps().is_none() {
+                        appsrc.set_caps(sample.caps_owned().as_ref());
+                    }
+                    // Every client has its own clock, let its appsrc timestamp the buffers
+                    let mut buffer = buffer.copy();
+                    {
+                        let buffer = buffer.get_mut().unwrap();
+                        buffer.set_pts(gstreamer::ClockTime::NONE);
+                        buffer.set_dts(gstreamer::ClockTime::NONE);
+                    }
+                    let _ = appsrc.push_buffer(buffer);
+                }
+                Ok(gstreamer::FlowSuccess::Ok)
+            })
+            .build(),
+    );
+}
+
+/// Find an appsrc of a client's media by name.
+fn media_appsrc(
+    media: &gstreamer_rtsp_server::RTSPMedia,
+    name: &str,
+) -> Option<gstreamer_app::AppSrc> {
+    media
+        .element()
+        .downcast::<gstreamer::Bin>()
+        .ok()
+        .and_then(|bin| bin.by_name(name))
+        .and_then(|src| src.downcast::<gstreamer_app::AppSrc>().ok())
+}
+
+/// Start serving the stream of the appsink, and the audio of the audio appsink if there is one,
+/// and tell the pipeline about clients through CLIENT_CONNECTED and CLIENT_DISCONNECTED messages
+/// on its bus. The valves of the RTSP branches are open while there are clients.
+pub fn serve(
+    opts: &RtspOptions,
+    codec: Codec,
+    pipeline: &gstreamer::Pipeline,
+    appsink: &gstreamer_app::AppSink,
+    audio: Option<(AudioCodec, &gstreamer_app::AppSink)>,
+    valves: Vec<gstreamer::Element>,
+) -> Result<Server, aError> {
+    let clients: Clients = Arc::new(Mutex::new(Vec::new()));
+    let audio_clients: Clients = Arc::new(Mutex::new(Vec::new()));
+    forward_samples(appsink, clients.clone());
+    if let Some((_, audio_appsink)) = audio {
+        forward_samples(audio_appsink, audio_clients.clone());
+    }
+
+    let parser = codec
+        .parser()
+        .map(|parser| format!("{parser} ! "))
+        .unwrap_or_default();
+    let config_interval = if matches!(codec, Codec::H264 | Codec::H265) {
+        " config-interval=-1"
+    } else {
+        ""
+    };
+    let audio_launch = audio
+        .map(|(audio_codec, _)| {
+            format!(
+                " appsrc name=audiosrc is-live=true do-timestamp=true format=time ! {} name=pay1 pt={}",
+                audio_codec.payloader(),
+                sdp::AUDIO_PAYLOAD_TYPE,
+            )
+        })
+        .unwrap_or_default();
+    let factory = gstreamer_rtsp_server::RTSPMediaFactory::new();
+    factory.set_launch(&format!(
+        "( appsrc name=src is-live=true do-timestamp=true format=time ! {parser}{} name=pay0 pt={}{config_interval}{audio_launch} )",
+        codec.payloader(),
+        sdp::PAYLOAD_TYPE,
+    ));
+    factory.set_shared(false);
+
+    let pipeline_weak = pipeline.downgrade();
+    let appsink_weak = appsink.downgrade();
+    factory.connect_media_configure(move |_, media| {
+        let Some(appsrc) = media_appsrc(media, "src") else {
+            log::error!("RTSP media without appsrc");
+            return;
+        };
+        {
+            let mut clients = clients.lock().unwrap();
+            clients.push(appsrc.clone());
+            if clients.len() == 1 {
+                set_valves(&valves, true);
+            }
+        }
+        let audio_appsrc = media_appsrc(media, "audiosrc");
+        if let Some(audio_appsrc) = &audio_appsrc {
+            audio_clients.lock().unwrap().push(audio_appsrc.clone());
+        }
+
+        // New clients can only start decoding from a keyframe
+        if let Some(appsink) = appsink_weak.upgrade() {
+            appsink.send_event(
+                gstreamer_video::UpstreamForceKeyUnitEvent::builder()
+                    .all_headers(true)
+                    .build(),
+            );
+        }
+        post_application_message(&pipeline_weak, CLIENT_CONNECTED);
+
+        let clients = clients.clone();
+        let audio_clients = audio_clients.clone();
+        let pipeline_weak = pipeline_weak.clone();
+        let valves = valves.clone();
+        media.connect_unprepared(move |_| {
+            {
+                let mut clients = clients.lock().unwrap();
+                clients.retain(|client| client != &appsrc);
+                if clients.is_empty() {
+                    set_valves(&valves, false);
+                }
+            }
+            audio_clients
+                .lock()
+                .unwrap()
+                .retain(|client| Some(client) != audio_appsrc.as_ref());
+            post_application_message(&pipeline_weak, CLIENT_DISCONNECTED);
+        });
+    });
+
+    let server = gstreamer_rtsp_server::RTSPServer::new();
+    server.set_address(&opts.address);
+    server.set_service(&opts.port.to_string());
+    server
+        .mount_points()
+        .ok_or_else(|| aError::msg("RTSP server without mount points"))?
+        .add_factory(&opts.path, factory);
+    server.attach(None)?;
+
+    // The server runs on the default main context, which nothing else iterates
+    let main_loop = glib::MainLoop::new(None, false);
+    thread::spawn({
+        let main_loop = main_loop.clone();
+        move || main_loop.run()
+    });
+
+    log::info!(
+        "Serving RTSP on rtsp://{}:{}{}",
+        address::url_host(&opts.address),
+        opts.port,
+        opts.path
+    );
+    Ok(Server {
+        _server: server,
+        main_loop,
+    })
+}
+
+fn set_valves(valves: &[gstreamer::Element], open: bool) {
+    for valve in valves {
+        valve.set_property("drop", !open);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closed_valve_lets_eos_through() {
+        gstreamer::init().unwrap();
+        let pipeline = gstreamer::Pipeline::default();
+        let src = gstreamer::ElementFactory::make("videotestsrc")
+            .property("is-live", true)
+            .build()
+            .unwrap();
+        let valve = make_valve().unwrap();
+        let sink = make_sink();
+        pipeline
+            .add_many(&[&src, &valve, sink.upcast_ref()])
+            .unwrap();
+        gstreamer::Element::link_many(&[&src, &valve, sink.upcast_ref()]).unwrap();
+        pipeline.set_state(gstreamer::State::Playing).unwrap();
+
+        // Like the first Ctrl-C of a --rtsp stream nobody is watching
+        pipeline.send_event(gstreamer::event::Eos::new());
+        let message = pipeline.bus().unwrap().timed_pop_filtered(
+            gstreamer::ClockTime::from_seconds(5),
+            &[gstreamer::MessageType::Eos, gstreamer::MessageType::Error],
+        );
+        pipeline.set_state(gstreamer::State::Null).unwrap();
+
+        assert_eq!(
+            message.map(|message| message.type_()),
+            Some(gstreamer::MessageType::Eos)
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use std::{fs, io, net::IpAddr, path::Path};
+
+/// RTP payload type used for the video stream, the first of the dynamic range.
+pub const PAYLOAD_TYPE: u32 = 96;
+/// RTP payload type used for the audio stream.
+pub const AUDIO_PAYLOAD_TYPE: u32 = 97;
+
+/// Audio sent next to the video, on a port of its own.
+pub struct AudioDescription<'a> {
+    pub port: u16,
+    /// RTP encoding name with clock rate and channels, e.g. OPUS/48000/2.
+    pub encoding: &'a str,
+}
+
+/// Describes an RTP stream so receivers like ffplay, VLC or gst-launch can pick it up.
+pub struct SessionDescription<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub multicast_ttl: Option<u8>,
+    /// RTP encoding name, e.g. H264.
+    pub encoding_name: &'a str,
+    /// Format specific parameters for the a=fmtp line.
+    pub format_parameters: Option<&'a str>,
+    pub audio: Option<AudioDescription<'a>>,
+}
+
+impl SessionDescription<'_> {
+    pub fn to_sdp(&self) -> String {
+        let address_type = match self.host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => "IP6",
+            _ => "IP4",
+        };
+        // IPv4 multicast connection addresses carry their TTL
+        let connection_address = match (self.host.parse::<IpAddr>(), self.multicast_ttl) {
+            (Ok(IpAddr::V4(addr)), Some(ttl)) if addr.is_multicast() => {
+                format!("{}/{}", self.host, ttl)
+            }
+            _ => self.host.to_string(),
+        };
+
+        let mut sdp = format!(
+            "v=0\r\n\
+             o=- 0 0 IN {address_type} {host}\r\n\
+             s=waystream\r\n\
+             c=IN {address_type} {connection_address}\r\n\
+             t=0 0\r\n\
+             m=video {port} RTP/AVP {pt}\r\n\
+             a=rtpmap:{pt} {encoding_name}/90000\r\n",
+            host = self.host,
+            port = self.port,
+            pt = PAYLOAD_TYPE,
+            encoding_name = self.encoding_name,
+        );
+        if let Some(format_parameters) = self.format_parameters {
+            sdp.push_str(&format!("a=fmtp:{PAYLOAD_TYPE} {format_parameters}\r\n"));
+        }
+        if let Some(audio) = &self.audio {
+            sdp.push_str(&format!(
+                "m=audio {port} RTP/AVP {AUDIO_PAYLOAD_TYPE}\r\n\
+                 a=rtpmap:{AUDIO_PAYLOAD_TYPE} {encoding}\r\n",
+                port = audio.port,
+                encoding = audio.encoding,
+            ));
+        }
+        sdp
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_sdp())
+    }
+}
@@ -0,0 +1,196 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::Error as aError;
+use gstreamer::prelude::*;
+
+use crate::{address, MissingElement};
+
+/// How the SRT connection is set up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SrtMode {
+    /// Connect to a receiver that listens.
+    Caller,
+    /// Wait for receivers to connect.
+    Listener,
+    /// Both sides connect to each other at the same time, which gets through firewalls.
+    Rendezvous,
+}
+
+impl SrtMode {
+    pub const ALL: [SrtMode; 3] = [SrtMode::Caller, SrtMode::Listener, SrtMode::Rendezvous];
+
+    /// Name of the mode in the URI, srtsink calls its modes the same.
+    pub fn name(self) -> &'static str {
+        match self {
+            SrtMode::Caller => "caller",
+            SrtMode::Listener => "listener",
+            SrtMode::Rendezvous => "rendezvous",
+        }
+    }
+}
+
+impl fmt::Display for SrtMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for SrtMode {
+    type Err = aError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SrtMode::ALL
+            .into_iter()
+            .find(|mode| mode.name() == s)
+            .ok_or_else(|| {
+                aError::msg(format!(
+                    "Unknown SRT mode {s}, expected caller, listener or rendezvous"
+                ))
+            })
+    }
+}
+
+/// Where and how the stream is sent over SRT, parsed from
+/// `srt://host:port?mode=...&latency=...&passphrase=...`.
+#[derive(Debug, Clone)]
+pub struct SrtOptions {
+    /// Host to connect to, or the address to listen on, empty for all of them.
+    pub host: String,
+    pub port: u16,
+    pub mode: SrtMode,
+    /// Time in ms the receiver buffers to retransmit lost packets, srtsink's default if none.
+    pub latency: Option<u32>,
+    /// Encrypts the stream, both sides need the same one.
+    pub passphrase: Option<String>,
+}
+
+impl SrtOptions {
+    /// URI of the connection without the parameters, which are set on srtsink directly.
+    pub fn uri(&self) -> String {
+        format!("srt://{}:{}", address::url_host(&self.host), self.port)
+    }
+}
+
+impl FromStr for SrtOptions {
+    type Err = aError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            aError::msg(format!(
+                "Invalid SRT URI {s}, expected srt://host:port with optional mode, latency and passphrase parameters"
+            ))
+        };
+
+        let rest = s.strip_prefix("srt://").ok_or_else(invalid)?;
+        let (authority, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let (host, port) = address::parse(authority.trim_end_matches('/')).ok_or_else(invalid)?;
+
+        let mut mode = None;
+        let mut latency = None;
+        let mut passphrase = None;
+        for parameter in query.split('&').filter(|parameter| !parameter.is_empty()) {
+            let (key, value) = parameter.split_once('=').ok_or_else(invalid)?;
+            match key {
+                "mode" => mode = Some(value.parse::<SrtMode>()?),
+                "latency" => latency = Some(value.parse().map_err(|_| invalid())?),
+                "passphrase" => passphrase = Some(value.to_string()),
+                _ => return Err(aError::msg(format!("Unknown SRT parameter {key}"))),
+            }
+        }
+
+        // Like srtsink, a URI without a host listens and one with a host calls
+        let mode = mode.unwrap_or(if host.is_empty() {
+            SrtMode::Listener
+        } else {
+            SrtMode::Caller
+        });
+        if host.is_empty() && mode != SrtMode::Listener {
+            return Err(aError::msg(format!(
+                "SRT {mode} mode needs the host of the receiver"
+            )));
+        }
+        // SRT rejects shorter and longer passphrases when it connects, tell right away
+        if let Some(passphrase) = &passphrase {
+            if !(10..=79).contains(&passphrase.len()) {
+                return Err(aError::msg("SRT passphrases are 10 to 79 characters long"));
+            }
+        }
+
+        Ok(SrtOptions {
+            host: host.to_string(),
+            port,
+            mode,
+            latency,
+            passphrase,
+        })
+    }
+}
+
+/// Create the srtsink the MPEG-TS stream is sent with.
+pub fn make_sink(opts: &SrtOptions) -> Result<gstreamer::Element, aError> {
+    let srtsink = gstreamer::ElementFactory::make("srtsink")
+        .property("uri", opts.uri())
+        // Without a receiver connected the stream is dropped instead of holding up the tee
+        .property("wait-for-connection", false)
+        .build()
+        .map_err(|_| MissingElement("srtsink"))?;
+    srtsink.set_property_from_str("mode", opts.mode.name());
+    if let Some(latency) = opts.latency {
+        srtsink.set_property("latency", latency as i32);
+    }
+    if let Some(passphrase) = &opts.passphrase {
+        srtsink.set_property("passphrase", passphrase);
+    }
+
+    match opts.mode {
+        SrtMode::Listener => log::info!("Waiting for SRT receivers on {}", opts.uri()),
+        mode => log::info!("Sending over SRT to {} as {mode}", opts.uri()),
+    }
+    Ok(srtsink)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_uris() {
+        let opts: SrtOptions = "srt://192.168.1.10:9000".parse().unwrap();
+        assert_eq!(
+            (opts.host.as_str(), opts.port, opts.mode),
+            ("192.168.1.10", 9000, SrtMode::Caller)
+        );
+        assert_eq!(opts.uri(), "srt://192.168.1.10:9000");
+
+        let opts: SrtOptions = "srt://:9000?latency=200&passphrase=0123456789"
+            .parse()
+            .unwrap();
+        assert_eq!(opts.mode, SrtMode::Listener);
+        assert_eq!(opts.latency, Some(200));
+        assert_eq!(opts.passphrase.as_deref(), Some("0123456789"));
+
+        let opts: SrtOptions = "srt://[::1]:9000/?mode=rendezvous".parse().unwrap();
+        assert_eq!(
+            (opts.host.as_str(), opts.mode),
+            ("::1", SrtMode::Rendezvous)
+        );
+        assert_eq!(opts.uri(), "srt://[::1]:9000");
+    }
+
+    #[test]
+    fn rejects_invalid_uris() {
+        for uri in [
+            "udp://host:9000",
+            "srt://host",
+            "srt://host:port",
+            "srt://:9000?mode=caller",
+            "srt://host:9000?mode=push",
+            "srt://host:9000?latency=soon",
+            "srt://host:9000?passphrase=short",
+            "srt://host:9000?streamid",
+            "srt://host:9000?pbkeylen=16",
+        ] {
+            assert!(uri.parse::<SrtOptions>().is_err(), "{uri} parsed");
+        }
+    }
+}
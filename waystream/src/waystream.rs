@@ -1,14 +1,16 @@
 use std::{
-    cmp, env,
+    env,
     error::Error,
-    process::exit,
-    time::{SystemTime, UNIX_EPOCH, Duration},
+    net::IpAddr,
+    path::PathBuf,
+    process::{self, exit},
+    time::Duration,
 };
 
 use libwayshot::CaptureRegion;
 use wayland_client::{
     globals::{registry_queue_init, GlobalList, GlobalListContents},
-    protocol::{wl_output::WlOutput, wl_registry},
+    protocol::{wl_output::WlOutput, wl_registry, wl_shm},
     Connection, QueueHandle,
 };
 
@@ -22,6 +24,23 @@ use gstreamer_video;
 #[display(fmt = "Missing element {}", _0)]
 struct MissingElement(#[error(not(source))] &'static str);
 
+/// Create an element of the factory with its default properties.
+fn make_element(factory: &'static str) -> Result<gstreamer::Element, aError> {
+    gstreamer::ElementFactory::make(factory)
+        .build()
+        .map_err(|_| MissingElement(factory).into())
+}
+
+/// Post an application message with an empty structure of the name on the pipeline bus, if the
+/// pipeline is still around.
+fn post_application_message(pipeline_weak: &glib::WeakRef<gstreamer::Pipeline>, name: &str) {
+    if let Some(pipeline) = pipeline_weak.upgrade() {
+        let _ = pipeline.post_message(gstreamer::message::Application::new(
+            gstreamer::Structure::new_empty(name),
+        ));
+    }
+}
+
 #[derive(Debug, Display, Error)]
 #[display(fmt = "Received error from {}: {} (debug: {:?})", src, error, debug)]
 struct ErrorMessage {
@@ -36,10 +55,63 @@ struct PipeOptions {
     target_width: i32,
     target_height: i32,
     show_fps: bool,
+    /// Target framerate of the stream, the maximum one at a variable rate.
+    fps: u32,
+    /// Keep-alive interval of a variable frame rate stream, which only gets frames when the
+    /// screen changed.
+    vfr: Option<Duration>,
+    /// Sink showing a local preview, none for headless streaming.
+    preview: Option<&'static str>,
+    damage: bool,
+    dmabuf: bool,
+    udp_host: Option<String>,
+    udp_port: u16,
+    multicast_ttl: Option<u8>,
+    multicast_iface: Option<String>,
+    rtp: bool,
+    /// Mux the encoded stream into MPEG-TS for the UDP target, in RTP with `rtp`.
+    mpegts: bool,
+    hls: Option<hls::HlsOptions>,
+    rtsp: Option<rtsp::RtspOptions>,
+    webrtc: Option<webrtc::WebRtcOptions>,
+    srt: Option<srt::SrtOptions>,
+    record: Option<record::RecordOptions>,
+    audio: Option<audio::AudioOptions>,
+    encoder: encoder::EncoderOptions,
+    sdp: PathBuf,
+}
+
+impl PipeOptions {
+    /// Whether RTSP and WebRTC, which only stream while clients are watching, are the only
+    /// outputs.
+    fn on_demand_only(&self) -> bool {
+        (self.rtsp.is_some() || self.webrtc.is_some())
+            && self.udp_host.is_none()
+            && self.hls.is_none()
+            && self.srt.is_none()
+            && self.record.is_none()
+            && self.preview.is_none()
+            && !self.show_fps
+    }
 }
 
+/// Posted on the pipeline bus when the user hits Ctrl-C.
+const INTERRUPTED: &str = "waystream-interrupted";
+
+mod address;
+mod audio;
 mod clap;
+mod dmabuf;
+mod encoder;
+mod hls;
+mod mpegts;
 mod output;
+mod pacing;
+mod record;
+mod rtsp;
+mod sdp;
+mod srt;
+mod webrtc;
 
 // TODO: Create a xdg-shell surface, check for the enter event, grab the output from it.
 
@@ -57,10 +129,6 @@ impl wayland_client::Dispatch<wl_registry::WlRegistry, GlobalListContents> for W
     }
 }
 
-struct IntersectingOutput {
-    output: WlOutput,
-    region: CaptureRegion,
-}
 #[derive(Clone)]
 #[derive(Debug)]
 enum CaptureInfo {
@@ -103,52 +171,455 @@ fn parse_geometry(g: &str) -> Option<libwayshot::CaptureRegion> {
     })
 }
 
+/// Let the user select a region with slurp and return the geometry it prints.
+fn run_slurp() -> Result<String, aError> {
+    let output = process::Command::new("slurp")
+        .output()
+        .map_err(|e| aError::msg(format!("Failed to run slurp: {e}")))?;
+    if !output.status.success() {
+        return Err(aError::msg(format!(
+            "slurp did not return a selection: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Bounding box of all outputs in the logical layout.
+fn layout_bounds(outputs: &[output::OutputInfo]) -> CaptureRegion {
+    let mut start_x = 0;
+    let mut start_y = 0;
+
+    let mut end_x = 0;
+    let mut end_y = 0;
+
+    for outputinfo in outputs {
+        if outputinfo.dimensions.x < start_x {
+            start_x = outputinfo.dimensions.x;
+        }
+        if outputinfo.dimensions.y < start_y {
+            start_y = outputinfo.dimensions.y;
+        }
+        if outputinfo.dimensions.x + outputinfo.dimensions.width > end_x {
+            end_x = outputinfo.dimensions.x + outputinfo.dimensions.width;
+        }
+        if outputinfo.dimensions.y + outputinfo.dimensions.height > end_y {
+            end_y = outputinfo.dimensions.y + outputinfo.dimensions.height;
+        }
+    }
+    CaptureRegion {
+        x_coordinate: start_x,
+        y_coordinate: start_y,
+        width: end_x - start_x,
+        height: end_y - start_y,
+    }
+}
+
+/// Check that a region is not empty and lies within the output layout.
+fn validate_region(region: &CaptureRegion, outputs: &[output::OutputInfo]) -> Result<(), aError> {
+    if region.width <= 0 || region.height <= 0 {
+        return Err(aError::msg(format!(
+            "Capture region {}x{} is empty",
+            region.width, region.height
+        )));
+    }
+
+    let bounds = layout_bounds(outputs);
+    if region.x_coordinate < bounds.x_coordinate
+        || region.y_coordinate < bounds.y_coordinate
+        || region.x_coordinate + region.width > bounds.x_coordinate + bounds.width
+        || region.y_coordinate + region.height > bounds.y_coordinate + bounds.height
+    {
+        return Err(aError::msg(format!(
+            "Capture region {},{} {}x{} is out of bounds, the outputs span {},{} {}x{}",
+            region.x_coordinate,
+            region.y_coordinate,
+            region.width,
+            region.height,
+            bounds.x_coordinate,
+            bounds.y_coordinate,
+            bounds.width,
+            bounds.height
+        )));
+    }
+
+    let output_geometries: Vec<libwayshot::OutputGeometry> = outputs
+        .iter()
+        .map(output_geometry)
+        .collect();
+    if libwayshot::intersect_region(region, &output_geometries).is_empty() {
+        return Err(aError::msg(
+            "Provided capture region doesn't intersect with any outputs!",
+        ));
+    }
+    Ok(())
+}
+
+fn output_geometry(output_info: &output::OutputInfo) -> libwayshot::OutputGeometry {
+    libwayshot::OutputGeometry {
+        output: output_info.wl_output.clone(),
+        geometry: CaptureRegion {
+            x_coordinate: output_info.dimensions.x,
+            y_coordinate: output_info.dimensions.y,
+            width: output_info.dimensions.width,
+            height: output_info.dimensions.height,
+        },
+    }
+}
+
+/// Logical layout of all outputs, used to split a capture region between them.
+fn output_geometries(
+    globals: &mut GlobalList,
+    conn: &mut Connection,
+) -> Vec<libwayshot::OutputGeometry> {
+    output::get_all_outputs(globals, conn)
+        .iter()
+        .map(output_geometry)
+        .collect()
+}
+
+/// Dmabuf frames are passed on as they are, so they can only come from a single output.
+fn dmabuf_capture_target(
+    area: &CaptureInfo,
+    output_regions: &[libwayshot::OutputRegion],
+) -> Option<(WlOutput, Option<CaptureRegion>)> {
+    match area {
+        CaptureInfo::Output(output) => Some((output.clone(), None)),
+        CaptureInfo::Region(_) => match output_regions {
+            [output_region] => Some((
+                output_region.output.clone(),
+                Some(output_region.capture_region),
+            )),
+            _ => {
+                log::warn!("Dmabuf capture needs a single output, falling back to shm");
+                None
+            }
+        },
+    }
+}
+
+/// Encode the audio of the tee for one output. The elements end in a sink of their own, or are
+/// linked to the request pad of a muxer that already takes the video.
+fn link_audio_branch(
+    pipeline: &gstreamer::Pipeline,
+    audio_tee: &gstreamer::Element,
+    elements: Vec<gstreamer::Element>,
+    muxer: Option<(&gstreamer::Element, &str)>,
+) -> Result<(), aError> {
+    let queue = gstreamer::ElementFactory::make("queue").build()?;
+    pipeline.add(&queue)?;
+    pipeline.add_many(&elements.iter().collect::<Vec<_>>())?;
+    let mut audio_branch = vec![audio_tee, &queue];
+    audio_branch.extend(elements.iter());
+    gstreamer::Element::link_many(&audio_branch)?;
+    if let (Some(last), Some((muxer, pad))) = (elements.last(), muxer) {
+        last.link_pads(None, muxer, Some(pad))?;
+    }
+    Ok(())
+}
+
+/// Map the wl_shm format of captured frames to the matching GStreamer video format.
+fn shm_video_format(format: wl_shm::Format) -> Option<gstreamer_video::VideoFormat> {
+    match format {
+        wl_shm::Format::Xrgb8888 => Some(gstreamer_video::VideoFormat::Bgrx),
+        wl_shm::Format::Argb8888 => Some(gstreamer_video::VideoFormat::Bgra),
+        wl_shm::Format::Xbgr8888 => Some(gstreamer_video::VideoFormat::Rgbx),
+        wl_shm::Format::Abgr8888 => Some(gstreamer_video::VideoFormat::Rgba),
+        // GStreamer has no 10 bit format with padding instead of alpha
+        wl_shm::Format::Xbgr2101010 | wl_shm::Format::Abgr2101010 => {
+            Some(gstreamer_video::VideoFormat::Rgb10a2Le)
+        }
+        _ => None,
+    }
+}
+
+/// Whether a captured frame has the size and row stride of the video frames, so its shm buffer
+/// can be pushed without copying it.
+fn frame_matches(frame_copy: &libwayshot::FrameCopy, video_info: &gstreamer_video::VideoInfo) -> bool {
+    let frame_format = frame_copy.frame_format;
+    frame_format.width == video_info.width()
+        && frame_format.height == video_info.height()
+        && frame_format.stride as i32 == video_info.stride()[0]
+        && frame_copy.data().len() >= video_info.size()
+}
+
 fn create_pipeline(mut conn: Connection,
                    mut globals: GlobalList,
                    area: CaptureInfo,
                    pipe_opts: PipeOptions,
-                   cursor_overlay: i32) -> Result<gstreamer::Pipeline, aError> {
+                   cursor_overlay: i32) -> Result<(gstreamer::Pipeline, Option<rtsp::Server>), aError> {
 
     gstreamer::init()?;
 
     let pipeline = gstreamer::Pipeline::default();
 
-    let video_info = gstreamer_video::VideoInfo::builder(gstreamer_video::VideoFormat::Rgbx, pipe_opts.width as u32, pipe_opts.height as u32)
-            //.fps(gstreamer::Fraction::new(25, 1))
+    encoder::probe();
+
+    let mut capture_session = libwayshot::CaptureSession::new(&globals, &conn, cursor_overlay)
+        .map_err(|e| aError::msg(e.to_string()))?;
+
+    // A region that is exactly one output, like the whole layout of a single output, is captured
+    // as that output. Its frames are pushed without copying them, at the output's size in pixels.
+    let geometries = output_geometries(&mut globals, &mut conn);
+    let area = match area {
+        CaptureInfo::Region(region) => match geometries
+            .iter()
+            .find(|output_geometry| output_geometry.geometry == region)
+        {
+            Some(output_geometry) => CaptureInfo::Output(output_geometry.output.clone()),
+            None => CaptureInfo::Region(region),
+        },
+        area => area,
+    };
+
+    // Split the region between the outputs it spans once, their frames are stitched together on
+    // a canvas the size of the region.
+    let (output_regions, mut canvas) = match &area {
+        CaptureInfo::Region(region) => {
+            let output_regions = libwayshot::intersect_region(region, &geometries);
+            if output_regions.is_empty() {
+                log::error!("Provided capture region doesn't intersect with any outputs!");
+                exit(1);
+            }
+            let canvas = libwayshot::Canvas::new(region.width as u32, region.height as u32);
+            (output_regions, Some(canvas))
+        }
+        CaptureInfo::Output(_) => (Vec::new(), None),
+    };
+
+    // Variable frame rate is signalled with a framerate of 0/1
+    let caps_fps = if pipe_opts.vfr.is_some() { 0 } else { pipe_opts.fps };
+
+    // Probe for dmabuf capture. It needs support from the compositor and the allocator, without
+    // either we stay with shm.
+    let dmabuf_target = if pipe_opts.dmabuf {
+        dmabuf_capture_target(&area, &output_regions)
+    } else {
+        None
+    };
+    let dmabuf_caps = dmabuf_target.as_ref().and_then(|(output, region)| {
+        match capture_session.capture_output_dmabuf(output, *region) {
+            Ok(Some(frame)) => dmabuf::caps(&frame, caps_fps),
+            Ok(None) => None,
+            Err(e) => {
+                log::warn!("Dmabuf capture failed, falling back to shm: {e}");
+                None
+            }
+        }
+    });
+    let dmabuf_allocator = dmabuf_caps
+        .as_ref()
+        .map(|_| gstreamer_allocators::DmaBufAllocator::new());
+
+    // Frames are pushed in the format the compositor picked, converting them is left to the
+    // videoconvert of every branch. Capture one to find out which format that is, and for a full
+    // output the size, which is its transformed mode.
+    let (shm_format, width, height) = match (&area, canvas.as_mut()) {
+        (CaptureInfo::Region(_), Some(canvas)) => {
+            capture_session
+                .capture_region(&output_regions, canvas)
+                .map_err(|e| aError::msg(e.to_string()))?;
+            (canvas.format, pipe_opts.width as u32, pipe_opts.height as u32)
+        }
+        (CaptureInfo::Output(output), _) => {
+            let frame_format = capture_session
+                .capture_output_frame(output, None)
+                .map_err(|e| aError::msg(e.to_string()))?
+                .frame_format;
+            (Some(frame_format.format), frame_format.width, frame_format.height)
+        }
+        (CaptureInfo::Region(_), None) => unreachable!("Region capture without canvas"),
+    };
+    let video_format = shm_format
+        .and_then(shm_video_format)
+        .ok_or_else(|| aError::msg(format!("Unsupported capture format {shm_format:?}")))?;
+    log::debug!("Capturing in {shm_format:?}, pushing {video_format}");
+    if pipe_opts.encoder.bit_depth == 10 && video_format != gstreamer_video::VideoFormat::Rgb10a2Le {
+        log::warn!("The compositor hands out 8 bit frames, encoding them with 10 bits adds no depth");
+    }
+
+    let video_info = gstreamer_video::VideoInfo::builder(video_format, width, height)
+            .fps(gstreamer::Fraction::new(caps_fps as i32, 1))
             .build()
             .expect("Failed to create video info");
 
     let appsrc = gstreamer_app::AppSrc::builder()
-        .caps(&video_info.to_caps().unwrap())
+        .caps(&dmabuf_caps.clone().unwrap_or_else(|| video_info.to_caps().unwrap()))
         .format(gstreamer::Format::Time)
+        .is_live(true)
         .build();
 
     // Convert for each sink
     let videoconvert_0 = gstreamer::ElementFactory::make("videoconvert").build()?;
     let videoconvert_1 = gstreamer::ElementFactory::make("videoconvert").build()?;
 
-    let videosink = gstreamer::ElementFactory::make("waylandsink").build()?;
-    let fpssink = gstreamer::ElementFactory::make("fpsdisplaysink").property("video-sink", &videosink)
-                                                                   .property("text-overlay", pipe_opts.show_fps)
-                                                                   .build()?;
+    // Without a preview the framerate is still measured on a fakesink and logged
+    let videosink = match (pipe_opts.preview, pipe_opts.show_fps) {
+        (Some(preview), _) => Some(gstreamer::ElementFactory::make(preview).build()?),
+        (None, true) => Some(gstreamer::ElementFactory::make("fakesink").build()?),
+        (None, false) => None,
+    };
+    let fpssink = match videosink {
+        Some(videosink) => {
+            let fpssink = gstreamer::ElementFactory::make("fpsdisplaysink")
+                .property("video-sink", &videosink)
+                .property(
+                    "text-overlay",
+                    pipe_opts.show_fps && pipe_opts.preview.is_some_and(|p| p != "fakesink"),
+                )
+                .property("signal-fps-measurements", pipe_opts.show_fps)
+                .property("fps-update-interval", 1000i32)
+                .build()?;
+            fpssink.connect("fps-measurements", false, |args| {
+                let fps = args[1].get::<f64>().unwrap();
+                let droprate = args[2].get::<f64>().unwrap();
+                let avgfps = args[3].get::<f64>().unwrap();
+                log::info!("{fps:.1} fps, {droprate:.1} dropped, {avgfps:.1} average");
+                None
+            });
+            Some(fpssink)
+        }
+        None => None,
+    };
+
+    let netsink = match &pipe_opts.udp_host {
+        Some(udp_host) => {
+            let netsink = gstreamer::ElementFactory::make("udpsink")
+                .property("host", udp_host)
+                .property("port", i32::from(pipe_opts.udp_port))
+                .build()?;
+            if let Some(ttl) = pipe_opts.multicast_ttl {
+                netsink.set_property("ttl-mc", i32::from(ttl));
+            }
+            if let Some(iface) = &pipe_opts.multicast_iface {
+                netsink.set_property("multicast-iface", iface);
+            }
+            Some(netsink)
+        }
+        None => None,
+    };
 
-    let hlssink = gstreamer::ElementFactory::make("hlssink2")
-        .name("hlssink")
-        .property("target-duration", 2u32)
-        .property("playlist-length", 2u32)
-        .property("max-files", 2u32)
-        .build()
-        .expect("Unable to instantiate hlssink");
+    // Audio goes over RTP on the next port pair after the video, unless it's muxed into MPEG-TS
+    let rtp_audio_port = match (&pipe_opts.audio, pipe_opts.rtp && !pipe_opts.mpegts) {
+        (Some(audio_opts), true) if audio_opts.codec.rtp_encoding().is_some() => Some(
+            pipe_opts
+                .udp_port
+                .checked_add(2)
+                .ok_or_else(|| aError::msg("No port left for the audio after the UDP port"))?,
+        ),
+        _ => None,
+    };
 
-    let netsink = gstreamer::ElementFactory::make("udpsink").property("host", "127.0.0.1")
-                                                            .property("port", 2490)
-                                                            .build()?;
+    // Encoded network branch: the selected codec over RTP, or MPEG-TS in datagrams or RTP
+    let (network_elements, network_mux) = match &pipe_opts.udp_host {
+        Some(udp_host) if pipe_opts.rtp || pipe_opts.mpegts => {
+            let codec = pipe_opts.encoder.codec;
+            let mut network_elements = vec![videoconvert_0];
+            network_elements.extend(encoder::make_encoder(&pipe_opts.encoder)?);
+            let mut network_mux = None;
+            if pipe_opts.mpegts {
+                let mux = mpegts::make_mux(
+                    codec,
+                    pipe_opts.audio.as_ref().map(|audio_opts| audio_opts.codec),
+                    network_elements.last().unwrap(),
+                )?;
+                network_elements.push(mux.clone());
+                network_mux = Some(mux);
+            }
 
-    let x264enc = gstreamer::ElementFactory::make("x264enc").build()
-                                                            .expect("Unable to instantiate x264enc");
+            if pipe_opts.rtp {
+                let (encoding_name, format_parameters) = if pipe_opts.mpegts {
+                    network_elements.push(mpegts::make_payloader()?);
+                    (mpegts::ENCODING_NAME, None)
+                } else {
+                    network_elements.push(encoder::make_payloader(codec)?);
+                    (codec.encoding_name(), codec.format_parameters())
+                };
 
-    let h264parse = gstreamer::ElementFactory::make("h264parse").build()
-                                                                 .expect("Unable to instantiate h264parse");
+                sdp::SessionDescription {
+                    host: udp_host,
+                    port: pipe_opts.udp_port,
+                    multicast_ttl: pipe_opts.multicast_ttl,
+                    encoding_name,
+                    format_parameters,
+                    audio: rtp_audio_port.zip(
+                        pipe_opts
+                            .audio
+                            .as_ref()
+                            .and_then(|audio_opts| audio_opts.codec.rtp_encoding()),
+                    )
+                    .map(|(port, encoding)| sdp::AudioDescription { port, encoding }),
+                }
+                .write(&pipe_opts.sdp)?;
+                log::info!("Wrote stream description to {}", pipe_opts.sdp.display());
+            }
+
+            (network_elements, network_mux)
+        }
+        _ => (Vec::new(), None),
+    };
+
+    // HLS branch, browsers only play H.264 reliably whatever codec goes over RTP
+    let hls_elements = if let Some(hls_opts) = &pipe_opts.hls {
+        let mut hls_elements = vec![videoconvert_1];
+        hls_elements.extend(encoder::make_encoder(&encoder::EncoderOptions {
+            codec: encoder::Codec::H264,
+            bit_depth: 8,
+            ..pipe_opts.encoder.clone()
+        })?);
+        hls_elements.extend(hls::make_sink(hls_opts)?);
+        hls_elements
+    } else {
+        Vec::new()
+    };
+
+    // RTSP branch, encoded once and handed out to every client by the server
+    let mut rtsp_valves = Vec::new();
+    let rtsp_elements = if pipe_opts.rtsp.is_some() {
+        let valve = rtsp::make_valve()?;
+        rtsp_valves.push(valve.clone());
+        let mut rtsp_elements =
+            vec![valve, gstreamer::ElementFactory::make("videoconvert").build()?];
+        rtsp_elements.extend(encoder::make_encoder(&pipe_opts.encoder)?);
+        rtsp_elements.push(rtsp::make_sink().upcast());
+        rtsp_elements
+    } else {
+        Vec::new()
+    };
+
+    // Recording branch, muxed into the container the file extension asks for
+    let (record_elements, record_mux) = if let Some(record_opts) = &pipe_opts.record {
+        let mut record_elements =
+            vec![gstreamer::ElementFactory::make("videoconvert").build()?];
+        record_elements.extend(encoder::make_encoder(&pipe_opts.encoder)?);
+        let record_sink = record::make_sink(
+            record_opts,
+            pipe_opts.encoder.codec,
+            pipe_opts.audio.as_ref().map(|audio_opts| audio_opts.codec),
+        )?;
+        let record_mux = record_sink[0].clone();
+        record_elements.extend(record_sink);
+        (record_elements, Some(record_mux))
+    } else {
+        (Vec::new(), None)
+    };
+
+    // SRT branch, muxed into MPEG-TS which every SRT receiver takes
+    let (srt_elements, srt_mux) = if let Some(srt_opts) = &pipe_opts.srt {
+        let mut srt_elements =
+            vec![gstreamer::ElementFactory::make("videoconvert").build()?];
+        srt_elements.extend(encoder::make_encoder(&pipe_opts.encoder)?);
+        let srt_mux = mpegts::make_mux(
+            pipe_opts.encoder.codec,
+            pipe_opts.audio.as_ref().map(|audio_opts| audio_opts.codec),
+            srt_elements.last().unwrap(),
+        )?;
+        srt_elements.push(srt_mux.clone());
+        srt_elements.push(srt::make_sink(srt_opts)?);
+        (srt_elements, Some(srt_mux))
+    } else {
+        (Vec::new(), None)
+    };
 
     let scale = gstreamer::ElementFactory::make("videoscale")
         .name("scale")
@@ -165,7 +636,7 @@ fn create_pipeline(mut conn: Connection,
         .build()
         .expect("Could not create caps element");
 
-    if pipe_opts.target_height > 0 && pipe_opts.target_height > 0 {
+    if pipe_opts.target_width > 0 && pipe_opts.target_height > 0 {
         filter.set_property("caps", &video_caps_scale);
     }
 
@@ -184,135 +655,327 @@ fn create_pipeline(mut conn: Connection,
     let video_tee_queue_2 = gstreamer::ElementFactory::make("queue")
         .build()?;
 
-    pipeline.add_many(&[appsrc.upcast_ref(), &scale,
-                                             &filter,
-                                             &video_tee,
-                                             &video_tee_queue_0,
-                                             &video_tee_queue_1,
-                                             //&video_tee_queue_2,
-                                             //&hlssink,
-                                             &netsink,
-                                             &fpssink])?;
-
-    gstreamer::Element::link_many(&[appsrc.upcast_ref(), &scale, &filter, &video_tee])?;
-    gstreamer::Element::link_many(&[&video_tee, &video_tee_queue_0, &netsink])?;
-    gstreamer::Element::link_many(&[&video_tee, &video_tee_queue_1, &fpssink])?;
-    //gstreamer::Element::link_many(&[&video_tee, &video_tee_queue_2, &hlssink])?;
-
-    let mut current_frame = 0;
-                    let mut t: u64 = 0;
+    pipeline.add_many(&[appsrc.upcast_ref(), &video_tee])?;
+
+    if dmabuf_caps.is_some() {
+        // videoscale only works on system memory, dmabufs go to the sinks as they are
+        if pipe_opts.target_width > 0 || pipe_opts.target_height > 0 {
+            log::warn!("Scaling is not supported for dmabuf capture, ignoring target size");
+        }
+        appsrc.link(&video_tee)?;
+    } else {
+        pipeline.add_many(&[&scale, &filter])?;
+        gstreamer::Element::link_many(&[appsrc.upcast_ref(), &scale, &filter, &video_tee])?;
+    }
+    if let Some(netsink) = &netsink {
+        let mut network_branch = vec![&video_tee, &video_tee_queue_0];
+        pipeline.add_many(&network_elements.iter().collect::<Vec<_>>())?;
+        pipeline.add_many(&[&video_tee_queue_0, netsink])?;
+        network_branch.extend(network_elements.iter());
+        network_branch.push(netsink);
+        gstreamer::Element::link_many(&network_branch)?;
+    }
+    if let Some(fpssink) = &fpssink {
+        pipeline.add_many(&[&video_tee_queue_1, fpssink])?;
+        if video_format == gstreamer_video::VideoFormat::Rgb10a2Le && dmabuf_caps.is_none() {
+            // Video sinks rarely take 10 bit RGB
+            let videoconvert = gstreamer::ElementFactory::make("videoconvert").build()?;
+            pipeline.add(&videoconvert)?;
+            gstreamer::Element::link_many(&[&video_tee, &video_tee_queue_1, &videoconvert, fpssink])?;
+        } else {
+            // Dmabufs go to the sink as they are, videoconvert only handles system memory
+            gstreamer::Element::link_many(&[&video_tee, &video_tee_queue_1, fpssink])?;
+        }
+    }
+    if !hls_elements.is_empty() {
+        pipeline.add(&video_tee_queue_2)?;
+        pipeline.add_many(&hls_elements.iter().collect::<Vec<_>>())?;
+        let mut hls_branch = vec![&video_tee, &video_tee_queue_2];
+        hls_branch.extend(hls_elements.iter());
+        gstreamer::Element::link_many(&hls_branch)?;
+    }
+    if !record_elements.is_empty() {
+        let video_tee_queue_4 = gstreamer::ElementFactory::make("queue").build()?;
+        pipeline.add(&video_tee_queue_4)?;
+        pipeline.add_many(&record_elements.iter().collect::<Vec<_>>())?;
+        let mut record_branch = vec![&video_tee, &video_tee_queue_4];
+        record_branch.extend(record_elements.iter());
+        gstreamer::Element::link_many(&record_branch)?;
+    }
+    if !srt_elements.is_empty() {
+        let video_tee_queue_5 = gstreamer::ElementFactory::make("queue").build()?;
+        pipeline.add(&video_tee_queue_5)?;
+        pipeline.add_many(&srt_elements.iter().collect::<Vec<_>>())?;
+        let mut srt_branch = vec![&video_tee, &video_tee_queue_5];
+        srt_branch.extend(srt_elements.iter());
+        gstreamer::Element::link_many(&srt_branch)?;
+    }
+    // Audio is captured once and encoded for every output that can carry it
+    let mut rtsp_audio_sink = None;
+    if let Some(audio_opts) = &pipe_opts.audio {
+        let audio_mix = audio::make_mix(&pipeline, &audio_opts.inputs)?;
+        pipeline.add(&audio_tee)?;
+        audio_mix.link(&audio_tee)?;
+
+        let mut audio_outputs = 0;
+        if let Some(record_mux) = &record_mux {
+            let elements = audio::make_encoder(audio_opts.codec, audio_opts.bitrate)?;
+            link_audio_branch(&pipeline, &audio_tee, elements, Some((record_mux, "audio_%u")))?;
+            audio_outputs += 1;
+        }
+        if let Some(srt_mux) = &srt_mux {
+            let elements = audio::make_encoder(audio_opts.codec, audio_opts.bitrate)?;
+            link_audio_branch(&pipeline, &audio_tee, elements, Some((srt_mux, "sink_%d")))?;
+            audio_outputs += 1;
+        }
+        match (&pipe_opts.hls, hls_elements.last()) {
+            (Some(hls_opts), _) if hls_opts.fmp4 => {
+                log::warn!("Fragmented MP4 HLS segments carry no audio");
+            }
+            (Some(_), Some(_)) if audio::AudioCodec::Aac.available_encoder().is_none() => {
+                log::warn!("No AAC encoder is installed, HLS segments carry no audio");
+            }
+            (Some(_), Some(hls_sink)) => {
+                // Like the video, HLS audio is what browsers play, AAC
+                let elements = audio::make_encoder(audio::AudioCodec::Aac, audio_opts.bitrate)?;
+                link_audio_branch(&pipeline, &audio_tee, elements, Some((hls_sink, "audio")))?;
+                audio_outputs += 1;
+            }
+            _ => {}
+        }
+        match (&pipe_opts.udp_host, rtp_audio_port, &network_mux) {
+            (Some(_), _, Some(network_mux)) => {
+                let elements = audio::make_encoder(audio_opts.codec, audio_opts.bitrate)?;
+                link_audio_branch(&pipeline, &audio_tee, elements, Some((network_mux, "sink_%d")))?;
+                log::info!("Muxing audio into the MPEG-TS stream");
+                audio_outputs += 1;
+            }
+            (Some(udp_host), Some(port), None) => {
+                let mut elements = audio::make_encoder(audio_opts.codec, audio_opts.bitrate)?;
+                elements.push(audio::make_payloader(audio_opts.codec)?);
+                let audio_netsink = gstreamer::ElementFactory::make("udpsink")
+                    .property("host", udp_host)
+                    .property("port", i32::from(port))
+                    .build()?;
+                if let Some(ttl) = pipe_opts.multicast_ttl {
+                    audio_netsink.set_property("ttl-mc", i32::from(ttl));
+                }
+                if let Some(iface) = &pipe_opts.multicast_iface {
+                    audio_netsink.set_property("multicast-iface", iface);
+                }
+                elements.push(audio_netsink);
+                link_audio_branch(&pipeline, &audio_tee, elements, None)?;
+                log::info!("Sending audio over RTP to port {port}");
+                audio_outputs += 1;
+            }
+            (Some(_), None, None) if pipe_opts.rtp => {
+                log::warn!("{} can't be described in the SDP, sending no audio over RTP, use opus", audio_opts.codec);
+            }
+            (Some(_), None, None) => {
+                log::warn!("Raw frames over UDP carry no audio, use --rtp or --mpegts")
+            }
+            (None, _, _) => {}
+        }
+        if pipe_opts.rtsp.is_some() {
+            let valve = rtsp::make_valve()?;
+            rtsp_valves.push(valve.clone());
+            let mut elements = vec![valve];
+            elements.extend(audio::make_encoder(audio_opts.codec, audio_opts.bitrate)?);
+            let appsink = rtsp::make_audio_sink();
+            elements.push(appsink.clone().upcast());
+            link_audio_branch(&pipeline, &audio_tee, elements, None)?;
+            rtsp_audio_sink = Some((audio_opts.codec, appsink));
+            audio_outputs += 1;
+        }
+        if pipe_opts.webrtc.is_some() {
+            // Browsers all play Opus, every viewer gets its own encoder
+            audio_outputs += 1;
+        }
+        if audio_outputs == 0 {
+            log::warn!("None of the outputs carries audio");
+        }
+    }
+
+    let rtsp_server = match (&pipe_opts.rtsp, rtsp_elements.last()) {
+        (Some(rtsp_opts), Some(rtsp_sink)) => {
+            let video_tee_queue_3 = gstreamer::ElementFactory::make("queue").build()?;
+            pipeline.add(&video_tee_queue_3)?;
+            pipeline.add_many(&rtsp_elements.iter().collect::<Vec<_>>())?;
+            let mut rtsp_branch = vec![&video_tee, &video_tee_queue_3];
+            rtsp_branch.extend(rtsp_elements.iter());
+            gstreamer::Element::link_many(&rtsp_branch)?;
+
+            let appsink = rtsp_sink
+                .clone()
+                .downcast::<gstreamer_app::AppSink>()
+                .unwrap();
+            let audio = rtsp_audio_sink
+                .as_ref()
+                .map(|(audio_codec, audio_appsink)| (*audio_codec, audio_appsink));
+            Some(rtsp::serve(
+                rtsp_opts,
+                pipe_opts.encoder.codec,
+                &pipeline,
+                &appsink,
+                audio,
+                rtsp_valves,
+            )?)
+        }
+        _ => None,
+    };
+
+    if let Some(webrtc_opts) = &pipe_opts.webrtc {
+        // Viewers get branches of their own off the tees while they are connected
+        let audio = pipe_opts
+            .audio
+            .as_ref()
+            .map(|audio_opts| (&audio_tee, audio_opts.bitrate));
+        webrtc::serve(webrtc_opts, &pipe_opts.encoder, &pipeline, &video_tee, audio)?;
+    }
+
+    let damage_tracking = pipe_opts.damage;
+    let mut pacer = match pipe_opts.vfr {
+        Some(keepalive) => pacing::FramePacer::variable(pipe_opts.fps, keepalive),
+        None => pacing::FramePacer::new(pipe_opts.fps),
+    };
+    // A capture takes up to one frame
+    appsrc.set_latency(pacer.frame_duration(), None);
+
     appsrc.set_callbacks(
         gstreamer_app::AppSrcCallbacks::builder()
             .need_data(move |appsrc, _| {
-                //log::info!("Frame {current_frame}");
-                let t0 = SystemTime::now();
-
-                let frame_copy: (Vec<libwayshot::FrameCopy>, Option<(i32, i32)>) = match &area {
-                    CaptureInfo::Region(region) => {
-                        let mut framecopys = Vec::new();
-
-                        let outputs = output::get_all_outputs(&mut globals, &mut conn);
-                        let mut intersecting_outputs: Vec<IntersectingOutput> = Vec::new();
-                        for output in outputs.iter() {
-                            let x1: i32 = cmp::max(output.dimensions.x, region.x_coordinate);
-                            let y1: i32 = cmp::max(output.dimensions.y, region.y_coordinate);
-                            let x2: i32 = cmp::min(
-                                output.dimensions.x + output.dimensions.width,
-                                region.x_coordinate + region.width,
-                            );
-                            let y2: i32 = cmp::min(
-                                output.dimensions.y + output.dimensions.height,
-                                region.y_coordinate + region.height,
-                            );
-
-                            let width = x2 - x1;
-                            let height = y2 - y1;
-
-                            if !(width <= 0 || height <= 0) {
-                                let true_x = region.x_coordinate - output.dimensions.x;
-                                let true_y = region.y_coordinate - output.dimensions.y;
-                                let true_region = CaptureRegion {
-                                    x_coordinate: true_x,
-                                    y_coordinate: true_y,
-                                    width: region.width,
-                                    height: region.height,
-                                };
-                                intersecting_outputs.push(IntersectingOutput {
-                                    output: output.wl_output.clone(),
-                                    region: true_region,
-                                });
-                            }
+                pacer.wait(appsrc);
+
+                if let (Some(allocator), Some((output, region))) = (&dmabuf_allocator, &dmabuf_target) {
+                    let buffer = match capture_session.capture_output_dmabuf(output, *region) {
+                        Ok(Some(frame)) => {
+                            let presentation_time = frame.presentation_time;
+                            dmabuf::frame_to_buffer(allocator, frame)
+                                .map(|buffer| (buffer, presentation_time))
                         }
-                        if intersecting_outputs.is_empty() {
-                            log::error!("Provided capture region doesn't intersect with any outputs!");
-                            exit(1);
+                        Ok(None) => Err(aError::msg("Dmabuf capture is no longer available")),
+                        Err(e) => Err(aError::msg(e.to_string())),
+                    };
+                    match buffer {
+                        Ok((buffer, presentation_time)) => {
+                            let _ = pacer.push(appsrc, Some(buffer), Some(presentation_time));
                         }
-
-                        for ouput_info in intersecting_outputs {
-                            framecopys.push(libwayshot::capture_output_frame(
-                                &mut globals,
-                                &mut conn,
-                                cursor_overlay,
-                                ouput_info.output.clone(),
-                                Some(ouput_info.region),
-                            ).unwrap());
+                        Err(e) => {
+                            log::error!("Failed to capture dmabuf frame: {e}");
+                            let _ = appsrc.end_of_stream();
                         }
-                        (framecopys, Some((region.width, region.height)))
                     }
-                    CaptureInfo::Output(output) => (
-                        vec![libwayshot::capture_output_frame(
-                            &mut globals,
-                            &mut conn,
-                            cursor_overlay,
-                            output.to_owned(),
-                            None,
-                        ).unwrap()],
-                        None,
-                    ),
+                    return;
+                }
+
+                // In damage mode only outputs with new content hand out a frame, waiting for it
+                // at most until the next frame is due or the keep-alive at a variable rate
+                let damage_timeout = Some(pacer.damage_timeout());
+                let capture = match (&area, canvas.as_mut()) {
+                    (CaptureInfo::Region(_), Some(canvas)) => {
+                        let damage = if damage_tracking {
+                            capture_session.capture_region_with_damage(
+                                &output_regions,
+                                canvas,
+                                damage_timeout,
+                            )
+                        } else {
+                            capture_session.capture_region(&output_regions, canvas)
+                        };
+                        damage.map(|damage| (None, !damage.is_empty()))
+                    }
+                    (CaptureInfo::Output(output), _) => {
+                        let frame_copy = if damage_tracking {
+                            capture_session
+                                .capture_output_frame_with_damage(output, None, damage_timeout)
+                        } else {
+                            capture_session.capture_output_frame(output, None).map(Some)
+                        };
+                        frame_copy.map(|frame_copy| {
+                            let changed = frame_copy.is_some();
+                            (frame_copy, changed)
+                        })
+                    }
+                    (CaptureInfo::Region(_), None) => unreachable!("Region capture without canvas"),
+                };
+                // Captures fail for good when an output goes away or changes its mode
+                let (output_frame, changed) = match capture {
+                    Ok(capture) => capture,
+                    Err(e) => {
+                        log::error!("Failed to capture frame: {e}");
+                        let _ = appsrc.end_of_stream();
+                        return;
+                    }
                 };
 
-                // Create the buffer that can hold exactly one RGBx/BGRx frame
+                if !changed {
+                    // Nothing changed on screen, repeat the last frame to hold the rate
+                    log::trace!("No damage, repeating the last frame");
+                    let _ = pacer.push(appsrc, None, None);
+                    return;
+                }
+
+                let presentation_time = match (&output_frame, &canvas) {
+                    (Some(frame_copy), _) => Some(frame_copy.presentation_time),
+                    (None, Some(canvas)) => canvas.presentation_time,
+                    (None, None) => None,
+                };
+
+                // Frames laid out like the video frame are pushed as they are, their shm buffer
+                // goes back to the capture ring once GStreamer releases it
+                let output_frame = match output_frame {
+                    Some(frame_copy) if frame_matches(&frame_copy, &video_info) => {
+                        let buffer = gstreamer::Buffer::from_slice(frame_copy);
+                        let _ = pacer.push(appsrc, Some(buffer), presentation_time);
+                        return;
+                    }
+                    output_frame => output_frame,
+                };
+
+                // Create the buffer that can hold exactly one frame
                 let mut buffer = gstreamer::Buffer::with_size(video_info.size()).unwrap();
                 {
                     let buffer = buffer.get_mut().unwrap();
-                    buffer.set_pts(current_frame * 20 * gstreamer::ClockTime::MSECOND);
 
                     let mut vframe =
                         gstreamer_video::VideoFrameRef::from_buffer_ref_writable(buffer, &video_info)
                             .unwrap();
 
-                    let width = vframe.width() as usize;
-                    let height = vframe.height() as usize;
+                    // Rows of the capture and the video frame may be padded differently
                     let stride = vframe.plane_stride()[0] as usize;
-
-                    let mut npixel: usize = 0;
-
-                    for line in vframe
-                        .plane_data_mut(0)
-                        .unwrap()
-                        .chunks_exact_mut(stride)
-                        .take(height)
-                    {
-                        for pixel in line[..(4 * width)].chunks_exact_mut(4) {
-                            pixel[0] = frame_copy.0[0].frame_mmap[npixel];
-                            pixel[1] = frame_copy.0[0].frame_mmap[npixel+1];
-                            pixel[2] = frame_copy.0[0].frame_mmap[npixel+2];
-                            pixel[3] = frame_copy.0[0].frame_mmap[npixel+3];
-                            npixel += 4
-                        }
+                    let plane = vframe.plane_data_mut(0).unwrap();
+                    match (&output_frame, &canvas) {
+                        (Some(frame_copy), _) => frame_copy.copy_to(plane, stride),
+                        (None, Some(canvas)) => canvas.copy_to(plane, stride),
+                        (None, None) => unreachable!("Captured neither a frame nor a region"),
                     }
                 }
-                current_frame += 1;
-                //println!("{:?}", t0.elapsed());
-                let _ = appsrc.push_buffer(buffer);
+                let _ = pacer.push(appsrc, Some(buffer), presentation_time);
             })
             .build(),
     );
-    Ok(pipeline)
+    Ok((pipeline, rtsp_server))
 }
 
-fn stream(pipeline: gstreamer::Pipeline) -> Result<(), aError> {
-    pipeline.set_state(gstreamer::State::Playing)?;
+fn stream(
+    pipeline: gstreamer::Pipeline,
+    _rtsp_server: Option<rtsp::Server>,
+    on_demand: bool,
+) -> Result<(), aError> {
+    // When RTSP and WebRTC are the only outputs there is only something to capture while clients
+    // are watching, until then the pipeline waits paused
+    if on_demand {
+        pipeline.set_state(gstreamer::State::Paused)?;
+    } else {
+        pipeline.set_state(gstreamer::State::Playing)?;
+    }
+    let mut clients: usize = 0;
+
+    // Ctrl-C ends the stream with EOS so muxers can finalise their files, a second one quits
+    // right away
+    let pipeline_weak = pipeline.downgrade();
+    ctrlc::set_handler(move || post_application_message(&pipeline_weak, INTERRUPTED))?;
+    let mut interrupted = false;
 
     let bus = pipeline
         .bus()
@@ -323,6 +986,38 @@ fn stream(pipeline: gstreamer::Pipeline) -> Result<(), aError> {
 
         match msg.view() {
             MessageView::Eos(..) => break,
+            MessageView::Application(app) => {
+                match app.structure().map(|s| s.name().as_str()) {
+                    Some(INTERRUPTED) if interrupted => {
+                        log::warn!("Interrupted again, quitting without finishing the stream");
+                        break;
+                    }
+                    Some(INTERRUPTED) => {
+                        interrupted = true;
+                        log::info!("Interrupted, finishing the stream");
+                        // A paused pipeline has to run to let the EOS through
+                        pipeline.set_state(gstreamer::State::Playing)?;
+                        pipeline.send_event(gstreamer::event::Eos::new());
+                    }
+                    Some(name @ (rtsp::CLIENT_CONNECTED | webrtc::VIEWER_CONNECTED)) => {
+                        clients += 1;
+                        let kind = if name == rtsp::CLIENT_CONNECTED { "RTSP" } else { "WebRTC" };
+                        log::info!("{kind} client connected, {clients} watching");
+                        if on_demand && clients == 1 {
+                            pipeline.set_state(gstreamer::State::Playing)?;
+                        }
+                    }
+                    Some(name @ (rtsp::CLIENT_DISCONNECTED | webrtc::VIEWER_DISCONNECTED)) => {
+                        clients = clients.saturating_sub(1);
+                        let kind = if name == rtsp::CLIENT_DISCONNECTED { "RTSP" } else { "WebRTC" };
+                        log::info!("{kind} client disconnected, {clients} watching");
+                        if on_demand && clients == 0 {
+                            pipeline.set_state(gstreamer::State::Paused)?;
+                        }
+                    }
+                    _ => (),
+                }
+            }
             MessageView::Error(err) => {
                 pipeline.set_state(gstreamer::State::Null)?;
                 return Err(ErrorMessage {
@@ -358,12 +1053,210 @@ fn main() -> Result<(), Box<dyn Error>> {
         target_width: 0,
         target_height: 0,
         show_fps: false,
+        fps: 30,
+        vfr: None,
+        preview: None,
+        damage: false,
+        dmabuf: false,
+        udp_host: None,
+        udp_port: 0,
+        multicast_ttl: None,
+        multicast_iface: None,
+        rtp: false,
+        mpegts: false,
+        hls: None,
+        rtsp: None,
+        webrtc: None,
+        srt: None,
+        record: None,
+        audio: None,
+        encoder: encoder::EncoderOptions {
+            codec: encoder::Codec::H264,
+            bitrate: 2048,
+            keyframe_interval: 60,
+            preset: String::from(encoder::PRESETS[0]),
+            bit_depth: 8,
+        },
+        sdp: PathBuf::from("waystream.sdp"),
     };
 
     if args.get_flag("show-fps") {
         pipe_opts.show_fps = true;
     }
 
+    pipe_opts.preview = match args.get_one::<String>("preview").map(String::as_str) {
+        Some("wayland") => Some("waylandsink"),
+        Some("auto") => Some("autovideosink"),
+        Some("fake") => Some("fakesink"),
+        _ => None,
+    };
+
+    if let Some(fps) = args.get_one::<u32>("fps") {
+        pipe_opts.fps = *fps;
+    }
+
+    if args.get_flag("damage") {
+        pipe_opts.damage = true;
+    }
+
+    if args.get_flag("vfr") {
+        let keepalive = *args.get_one::<u64>("keepalive").unwrap();
+        pipe_opts.vfr = Some(Duration::from_millis(keepalive));
+        pipe_opts.damage = true;
+    }
+
+    if args.get_flag("dmabuf") {
+        pipe_opts.dmabuf = true;
+    }
+
+    // udpsink takes hostnames and IPv6 addresses, but not in the bracketed URL form
+    if let (Some(udp_host), Some(udp_port)) = (
+        args.get_one::<String>("udp-host"),
+        args.get_one::<u16>("udp-port"),
+    ) {
+        let udp_host = udp_host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(udp_host)
+            .to_string();
+        pipe_opts.udp_port = *udp_port;
+        pipe_opts.multicast_ttl = args.get_one::<u8>("multicast-ttl").copied();
+        pipe_opts.multicast_iface = args.get_one::<String>("multicast-iface").cloned();
+
+        let is_multicast = udp_host
+            .parse::<IpAddr>()
+            .is_ok_and(|addr| addr.is_multicast());
+        if !is_multicast
+            && (pipe_opts.multicast_ttl.is_some() || pipe_opts.multicast_iface.is_some())
+        {
+            log::warn!("{udp_host} is not a multicast address, ignoring the multicast options");
+            pipe_opts.multicast_ttl = None;
+            pipe_opts.multicast_iface = None;
+        }
+        pipe_opts.udp_host = Some(udp_host);
+    }
+
+    if let Some(rtsp) = args.get_one::<String>("rtsp") {
+        match rtsp.parse::<rtsp::RtspOptions>() {
+            Ok(rtsp_opts) => pipe_opts.rtsp = Some(rtsp_opts),
+            Err(e) => {
+                log::error!("{e}");
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(webrtc) = args.get_one::<String>("webrtc") {
+        match webrtc.parse::<webrtc::WebRtcOptions>() {
+            Ok(webrtc_opts) => pipe_opts.webrtc = Some(webrtc_opts),
+            Err(e) => {
+                log::error!("{e}");
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(srt) = args.get_one::<String>("srt") {
+        match srt.parse::<srt::SrtOptions>() {
+            Ok(srt_opts) => pipe_opts.srt = Some(srt_opts),
+            Err(e) => {
+                log::error!("{e}");
+                exit(1);
+            }
+        }
+    }
+
+    let audio_inputs = args
+        .get_many::<String>("audio")
+        .unwrap_or_default()
+        .filter(|input| *input != "none")
+        .map(|input| input.parse::<audio::AudioInput>())
+        .collect::<Result<Vec<_>, _>>();
+    match audio_inputs {
+        Ok(inputs) if inputs.is_empty() => {}
+        Ok(inputs) => {
+            pipe_opts.audio = Some(audio::AudioOptions {
+                inputs,
+                codec: args
+                    .get_one::<audio::AudioCodec>("audio-codec")
+                    .copied()
+                    .unwrap_or(audio::AudioCodec::Opus),
+                bitrate: args.get_one::<u32>("audio-bitrate").copied().unwrap_or(128),
+            })
+        }
+        Err(e) => {
+            log::error!("{e}");
+            exit(1);
+        }
+    }
+
+    if args.get_flag("rtp") {
+        pipe_opts.rtp = true;
+    }
+
+    if args.get_flag("mpegts") {
+        pipe_opts.mpegts = true;
+    }
+
+    if let Some(dir) = args.get_one::<PathBuf>("hls") {
+        pipe_opts.hls = Some(hls::HlsOptions {
+            dir: dir.clone(),
+            segment_duration: *args.get_one::<u32>("hls-segment-duration").unwrap(),
+            playlist_length: *args.get_one::<u32>("hls-playlist-length").unwrap(),
+            location: args.get_one::<String>("hls-location").cloned(),
+            fmp4: args.get_flag("hls-fmp4") || args.get_flag("hls-ll"),
+            part_duration: args
+                .get_flag("hls-ll")
+                .then(|| *args.get_one::<u32>("hls-part-duration").unwrap()),
+        });
+    }
+
+    if let Some(path) = args.get_one::<PathBuf>("record") {
+        pipe_opts.record = Some(record::RecordOptions {
+            path: path.clone(),
+            segment_duration: args.get_one::<u64>("segment-duration").copied(),
+            segment_size: args.get_one::<u64>("segment-size").copied(),
+            max_segments: args.get_one::<u32>("max-segments").copied(),
+        });
+    }
+
+    if pipe_opts.dmabuf
+        && (pipe_opts.rtp
+            || pipe_opts.mpegts
+            || pipe_opts.hls.is_some()
+            || pipe_opts.rtsp.is_some()
+            || pipe_opts.webrtc.is_some()
+            || pipe_opts.srt.is_some()
+            || pipe_opts.record.is_some())
+    {
+        log::warn!("The encoder needs frames in system memory, disabling dmabuf capture");
+        pipe_opts.dmabuf = false;
+    }
+
+    if let Some(codec) = args.get_one::<encoder::Codec>("codec") {
+        pipe_opts.encoder.codec = *codec;
+    }
+
+    if let Some(bitrate) = args.get_one::<u32>("bitrate") {
+        pipe_opts.encoder.bitrate = *bitrate;
+    }
+
+    if let Some(keyframe_interval) = args.get_one::<u32>("keyframe-interval") {
+        pipe_opts.encoder.keyframe_interval = *keyframe_interval;
+    }
+
+    if let Some(preset) = args.get_one::<String>("preset") {
+        pipe_opts.encoder.preset = preset.clone();
+    }
+
+    if let Some(bit_depth) = args.get_one::<u32>("bit-depth") {
+        pipe_opts.encoder.bit_depth = *bit_depth;
+    }
+
+    if let Some(sdp) = args.get_one::<PathBuf>("sdp") {
+        pipe_opts.sdp = sdp.clone();
+    }
+
     if args.contains_id("width") {
         pipe_opts.target_width = args.get_one::<i32>("width").unwrap().clone();
     }
@@ -385,50 +1278,38 @@ fn main() -> Result<(), Box<dyn Error>> {
         exit(1);
     }
 
-    let output: WlOutput = if args.contains_id("output") {
-        output::get_wloutput(
-            args.get_one::<String>("output").unwrap().clone(),
-            output::get_all_outputs(&mut globals, &mut conn),
-        )
-    } else { output::get_all_outputs(&mut globals, &mut conn) .first()
-            .unwrap()
-            .wl_output
-            .clone()
-    };
-
     let mut cursor_overlay: i32 = 0;
     if args.get_flag("cursor") {
         cursor_overlay = 1;
     }
 
-    let capture_area = {
-        let mut start_x = 0;
-        let mut start_y = 0;
-
-        let mut end_x = 0;
-        let mut end_y = 0;
-
-        let output_infos = output::get_all_outputs(&mut globals, &mut conn);
-        for outputinfo in output_infos {
-            if outputinfo.dimensions.x < start_x {
-                start_x = outputinfo.dimensions.x;
-            }
-            if outputinfo.dimensions.y < start_y {
-                start_y = outputinfo.dimensions.y;
-            }
-            if outputinfo.dimensions.x + outputinfo.dimensions.width > end_x {
-                end_x = outputinfo.dimensions.x + outputinfo.dimensions.width;
-            }
-            if outputinfo.dimensions.y + outputinfo.dimensions.height > end_y {
-                end_y = outputinfo.dimensions.y + outputinfo.dimensions.height;
-            }
+    let output_infos = output::get_all_outputs(&mut globals, &mut conn);
+    let capture_area = if let Some(name) = args.get_one::<String>("output") {
+        // The size of a full output capture is taken from its first frame
+        CaptureInfo::Output(output::get_wloutput(name.clone(), output_infos))
+    } else if args.contains_id("slurp") {
+        // Without a geometry we ask slurp for one ourselves
+        let geometry = match args.get_one::<String>("slurp") {
+            Some(geometry) => geometry.clone(),
+            None => run_slurp().unwrap_or_else(|e| {
+                log::error!("{e}");
+                exit(1);
+            }),
+        };
+        let Some(region) = parse_geometry(&geometry) else {
+            log::error!(
+                "Invalid geometry \"{}\", expected \"x,y wxh\" or \"x y w h\"",
+                geometry.trim()
+            );
+            exit(1);
+        };
+        if let Err(e) = validate_region(&region, &output_infos) {
+            log::error!("{e}");
+            exit(1);
         }
-        CaptureInfo::Region(CaptureRegion {
-            x_coordinate: start_x,
-            y_coordinate: start_y,
-            width: end_x - start_x,
-            height: end_y - start_y,
-        })
+        CaptureInfo::Region(region)
+    } else {
+        CaptureInfo::Region(layout_bounds(&output_infos))
     };
 
     if let CaptureInfo::Region(r) = capture_area {
@@ -437,11 +1318,13 @@ fn main() -> Result<(), Box<dyn Error>> {
     };
 
 
+    let on_demand = pipe_opts.on_demand_only();
     match create_pipeline(conn,
                           globals,
                           capture_area,
                           pipe_opts,
-                          cursor_overlay).and_then(stream) {
+                          cursor_overlay)
+        .and_then(|(pipeline, rtsp_server)| stream(pipeline, rtsp_server, on_demand)) {
         Ok(r) => r,
         Err(e) => eprintln!("Error running pipeline: {e}"),
     }
@@ -0,0 +1,452 @@
+use std::{
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::Error as aError;
+use gstreamer::{glib, prelude::*};
+use serde_json::{json, Value};
+use tungstenite::{Message, WebSocket};
+
+use crate::{
+    address,
+    audio::{self, AudioCodec},
+    encoder::{self, Codec, EncoderOptions},
+    make_element, post_application_message, MissingElement,
+};
+
+/// Posted on the pipeline bus when a viewer's peer connection has been added.
+pub const VIEWER_CONNECTED: &str = "waystream-webrtc-viewer-connected";
+/// Posted on the pipeline bus when a viewer's peer connection has been removed.
+pub const VIEWER_DISCONNECTED: &str = "waystream-webrtc-viewer-disconnected";
+
+/// Page the browser loads, it connects back to the signalling endpoint and plays the stream.
+const PAGE: &str = include_str!("webrtc.html");
+/// Path of the WebSocket signalling endpoint.
+const SIGNALLING_PATH: &str = "/ws";
+/// How long a read of the signalling socket may block before queued messages are sent.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Where the viewer page and the signalling are served, parsed from `address:port`.
+#[derive(Debug, Clone)]
+pub struct WebRtcOptions {
+    pub address: String,
+    pub port: u16,
+}
+
+impl FromStr for WebRtcOptions {
+    type Err = aError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || aError::msg(format!("Invalid WebRTC address {s}, expected address:port"));
+
+        let (address, port) = address::parse(s)
+            .filter(|(address, _)| !address.is_empty())
+            .ok_or_else(invalid)?;
+
+        Ok(WebRtcOptions {
+            address: address.to_string(),
+            port,
+        })
+    }
+}
+
+/// Everything a viewer's peer connection is built from.
+struct Viewers {
+    pipeline: glib::WeakRef<gstreamer::Pipeline>,
+    video_tee: gstreamer::Element,
+    encoder: EncoderOptions,
+    /// Tee of the audio mix and the Opus bitrate, if there is audio.
+    audio: Option<(gstreamer::Element, u32)>,
+}
+
+/// Start serving the viewer page and the signalling on a thread of their own. Every viewer gets
+/// a webrtcbin fed from the video tee, and from the audio tee if there is one, for as long as
+/// its signalling connection stays open.
+pub fn serve(
+    opts: &WebRtcOptions,
+    encoder: &EncoderOptions,
+    pipeline: &gstreamer::Pipeline,
+    video_tee: &gstreamer::Element,
+    audio: Option<(&gstreamer::Element, u32)>,
+) -> Result<(), aError> {
+    if gstreamer::ElementFactory::find("webrtcbin").is_none() {
+        return Err(MissingElement("webrtcbin").into());
+    }
+    if audio.is_some() && AudioCodec::Opus.available_encoder().is_none() {
+        return Err(aError::msg("WebRTC audio needs opusenc"));
+    }
+
+    let listener = TcpListener::bind((opts.address.as_str(), opts.port))?;
+    let viewers = Arc::new(Viewers {
+        pipeline: pipeline.downgrade(),
+        video_tee: video_tee.clone(),
+        encoder: encoder.clone(),
+        audio: audio.map(|(tee, bitrate)| (tee.clone(), bitrate)),
+    });
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("Failed to accept a WebRTC connection: {e}");
+                    continue;
+                }
+            };
+            let viewers = viewers.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle_connection(stream, &viewers) {
+                    log::warn!("WebRTC connection failed: {e}");
+                }
+            });
+        }
+    });
+
+    log::info!(
+        "Serving WebRTC on http://{}:{}/",
+        address::url_host(&opts.address),
+        opts.port
+    );
+    Ok(())
+}
+
+/// Answer a page load, or take over the connection for signalling if it asks for a WebSocket.
+fn handle_connection(mut stream: TcpStream, viewers: &Viewers) -> Result<(), aError> {
+    // Peek so the WebSocket handshake still sees the whole request
+    let mut request = [0; 4096];
+    let len = stream.peek(&mut request)?;
+    let request = String::from_utf8_lossy(&request[..len]);
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+    if path == SIGNALLING_PATH {
+        let socket = tungstenite::accept(stream)?;
+        return signal(socket, viewers);
+    }
+
+    let mut discard = [0; 4096];
+    let _ = stream.read(&mut discard)?;
+    let response = match path {
+        "/" | "/index.html" => format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{PAGE}",
+            PAGE.len()
+        ),
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes())?;
+    Ok(())
+}
+
+/// Relay the signalling of one viewer until either side hangs up, then remove its peer.
+fn signal(mut socket: WebSocket<TcpStream>, viewers: &Viewers) -> Result<(), aError> {
+    // webrtcbin answers from its own threads, its messages are queued and sent between reads
+    let (sender, outgoing) = mpsc::channel::<Value>();
+    socket.get_mut().set_read_timeout(Some(POLL_INTERVAL))?;
+    let mut peer = None;
+
+    let result = (|| -> Result<(), aError> {
+        loop {
+            for message in outgoing.try_iter() {
+                socket.send(Message::Text(message.to_string()))?;
+            }
+
+            let text = match socket.read() {
+                Ok(Message::Text(text)) => text,
+                Ok(Message::Close(_)) | Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+                Ok(_) => continue,
+                Err(tungstenite::Error::Io(e))
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let message: Value = serde_json::from_str(&text)?;
+
+            match (message["type"].as_str(), &peer) {
+                (Some("hello"), None) => {
+                    let codecs: Vec<&str> = message["codecs"]
+                        .as_array()
+                        .map(|codecs| codecs.iter().filter_map(Value::as_str).collect())
+                        .unwrap_or_default();
+                    let Some(codec) = pick_codec(viewers.encoder.codec, &codecs) else {
+                        socket.send(Message::Text(
+                            json!({
+                                "type": "error",
+                                "message": "The browser plays neither H.264 nor VP8",
+                            })
+                            .to_string(),
+                        ))?;
+                        return Ok(());
+                    };
+                    peer = Some(Peer::add(viewers, codec, sender.clone())?);
+                    log::info!(
+                        "WebRTC viewer {} connected, sending {codec}",
+                        socket.get_ref().peer_addr()?
+                    );
+                }
+                (Some("answer"), Some(peer)) => {
+                    let sdp = message["sdp"]
+                        .as_str()
+                        .ok_or_else(|| aError::msg("WebRTC answer without SDP"))?;
+                    peer.set_answer(sdp)?;
+                }
+                (Some("ice"), Some(peer)) => {
+                    if let (Some(candidate), Some(mline_index)) = (
+                        message["candidate"].as_str(),
+                        message["sdpMLineIndex"].as_u64(),
+                    ) {
+                        peer.add_ice_candidate(mline_index as u32, candidate);
+                    }
+                }
+                (kind, _) => log::warn!("Unexpected WebRTC signalling message {kind:?}"),
+            }
+        }
+    })();
+
+    if let Some(peer) = peer {
+        peer.remove(viewers);
+        log::info!("WebRTC viewer disconnected");
+    }
+    let _ = socket.close(None);
+    result
+}
+
+/// Take the configured codec if the browser plays it, otherwise whichever of the codecs every
+/// browser supports is available.
+fn pick_codec(preferred: Codec, browser_codecs: &[&str]) -> Option<Codec> {
+    let plays = |codec: Codec| {
+        browser_codecs
+            .iter()
+            .any(|mime| mime.eq_ignore_ascii_case(&format!("video/{}", codec.encoding_name())))
+    };
+    [preferred, Codec::H264, Codec::Vp8]
+        .into_iter()
+        .filter(|codec| matches!(codec, Codec::H264 | Codec::Vp8))
+        .find(|codec| plays(*codec) && codec.available_encoder().is_some())
+}
+
+/// The peer connection of one viewer, a bin of its own encoders and webrtcbin hanging off the
+/// tees.
+struct Peer {
+    bin: gstreamer::Bin,
+    webrtcbin: gstreamer::Element,
+    /// Request pads of the tees and the tees they belong to.
+    tee_pads: Vec<(gstreamer::Element, gstreamer::Pad)>,
+}
+
+impl Peer {
+    fn add(viewers: &Viewers, codec: Codec, sender: mpsc::Sender<Value>) -> Result<Self, aError> {
+        let pipeline = viewers
+            .pipeline
+            .upgrade()
+            .ok_or_else(|| aError::msg("Pipeline is gone"))?;
+
+        let bin = gstreamer::Bin::new(None);
+        let webrtcbin = gstreamer::ElementFactory::make("webrtcbin")
+            .build()
+            .map_err(|_| MissingElement("webrtcbin"))?;
+        // No STUN or TURN server, host candidates reach viewers on the same machine or network
+        webrtcbin.set_property_from_str("bundle-policy", "max-bundle");
+        bin.add(&webrtcbin)?;
+        connect_signalling(&webrtcbin, sender);
+
+        // Browsers play 8 bit video only, and H.264 in the constrained baseline profile
+        let mut video = vec![make_element("videoconvert")?];
+        video.extend(encoder::make_encoder(&EncoderOptions {
+            codec,
+            bit_depth: 8,
+            ..viewers.encoder.clone()
+        })?);
+        if matches!(codec, Codec::H264) {
+            video.push(
+                gstreamer::ElementFactory::make("capsfilter")
+                    .property(
+                        "caps",
+                        gstreamer::Caps::builder("video/x-h264")
+                            .field("profile", "constrained-baseline")
+                            .build(),
+                    )
+                    .build()?,
+            );
+        }
+        video.push(encoder::make_payloader(codec)?);
+
+        let mut branches = vec![(
+            viewers.video_tee.clone(),
+            add_branch(&bin, &webrtcbin, video)?,
+        )];
+        if let Some((audio_tee, bitrate)) = &viewers.audio {
+            let mut audio = audio::make_encoder(AudioCodec::Opus, *bitrate)?;
+            audio.push(audio::make_payloader(AudioCodec::Opus)?);
+            branches.push((audio_tee.clone(), add_branch(&bin, &webrtcbin, audio)?));
+        }
+        set_send_only(&webrtcbin);
+
+        pipeline.add(&bin)?;
+        bin.sync_state_with_parent()?;
+        let mut tee_pads = Vec::new();
+        for (tee, ghost_pad) in branches {
+            let tee_pad = tee
+                .request_pad_simple("src_%u")
+                .ok_or_else(|| aError::msg("Failed to request a tee pad"))?;
+            tee_pad.link(&ghost_pad)?;
+            tee_pads.push((tee, tee_pad));
+        }
+
+        post_application_message(&viewers.pipeline, VIEWER_CONNECTED);
+        Ok(Peer {
+            bin,
+            webrtcbin,
+            tee_pads,
+        })
+    }
+
+    fn set_answer(&self, sdp: &str) -> Result<(), aError> {
+        let sdp = gstreamer_sdp::SDPMessage::parse_buffer(sdp.as_bytes())?;
+        let answer = gstreamer_webrtc::WebRTCSessionDescription::new(
+            gstreamer_webrtc::WebRTCSDPType::Answer,
+            sdp,
+        );
+        self.webrtcbin.emit_by_name::<()>(
+            "set-remote-description",
+            &[&answer, &None::<gstreamer::Promise>],
+        );
+        Ok(())
+    }
+
+    fn add_ice_candidate(&self, mline_index: u32, candidate: &str) {
+        self.webrtcbin
+            .emit_by_name::<()>("add-ice-candidate", &[&mline_index, &candidate]);
+    }
+
+    /// Unlink the peer from the tees and take it out of the pipeline. The tee pads are unlinked
+    /// from IDLE probes, while no buffer passes them, and the bin is shut down after the last one.
+    fn remove(self, viewers: &Viewers) {
+        let Peer { bin, tee_pads, .. } = self;
+        if tee_pads.is_empty() {
+            shut_down(&bin, viewers.pipeline.clone());
+            return;
+        }
+
+        let remaining = Arc::new(AtomicUsize::new(tee_pads.len()));
+        for (tee, tee_pad) in tee_pads {
+            let bin = bin.clone();
+            let pipeline = viewers.pipeline.clone();
+            let remaining = remaining.clone();
+            tee_pad.add_probe(gstreamer::PadProbeType::IDLE, move |tee_pad, _| {
+                if let Some(peer_pad) = tee_pad.peer() {
+                    let _ = tee_pad.unlink(&peer_pad);
+                }
+                tee.release_request_pad(tee_pad);
+                if remaining.fetch_sub(1, Ordering::SeqCst) == 1 {
+                    shut_down(&bin, pipeline.clone());
+                }
+                gstreamer::PadProbeReturn::Remove
+            });
+        }
+    }
+}
+
+/// Stop an unlinked peer bin and remove it from the pipeline. Probes run on streaming threads,
+/// which must not change states, so it happens asynchronously.
+fn shut_down(bin: &gstreamer::Bin, pipeline: glib::WeakRef<gstreamer::Pipeline>) {
+    bin.call_async(move |bin| {
+        let _ = bin.set_state(gstreamer::State::Null);
+        if let Some(pipeline) = pipeline.upgrade() {
+            let _ = pipeline.remove(bin);
+        }
+        post_application_message(&pipeline, VIEWER_DISCONNECTED);
+    });
+}
+
+/// Link a queue and the elements into a new sink pad of webrtcbin, and return the ghost pad the
+/// branch is fed through.
+fn add_branch(
+    bin: &gstreamer::Bin,
+    webrtcbin: &gstreamer::Element,
+    elements: Vec<gstreamer::Element>,
+) -> Result<gstreamer::GhostPad, aError> {
+    let mut branch = vec![make_element("queue")?];
+    branch.extend(elements);
+    bin.add_many(&branch.iter().collect::<Vec<_>>())?;
+    gstreamer::Element::link_many(&branch.iter().collect::<Vec<_>>())?;
+    branch.last().unwrap().link(webrtcbin)?;
+
+    let ghost_pad = gstreamer::GhostPad::with_target(None, &branch[0].static_pad("sink").unwrap())?;
+    bin.add_pad(&ghost_pad)?;
+    Ok(ghost_pad)
+}
+
+/// The viewers only receive, make every transceiver offer send-only media.
+fn set_send_only(webrtcbin: &gstreamer::Element) {
+    for index in 0i32.. {
+        let transceiver = webrtcbin.emit_by_name::<Option<gstreamer_webrtc::WebRTCRTPTransceiver>>(
+            "get-transceiver",
+            &[&index],
+        );
+        let Some(transceiver) = transceiver else {
+            break;
+        };
+        transceiver.set_property(
+            "direction",
+            gstreamer_webrtc::WebRTCRTPTransceiverDirection::Sendonly,
+        );
+    }
+}
+
+/// Offer the stream once webrtcbin is ready to negotiate, and pass on its ICE candidates.
+fn connect_signalling(webrtcbin: &gstreamer::Element, sender: mpsc::Sender<Value>) {
+    let offer_sender = sender.clone();
+    webrtcbin.connect("on-negotiation-needed", false, move |values| {
+        let webrtcbin = values[0].get::<gstreamer::Element>().unwrap();
+        let sender = offer_sender.clone();
+        let promise = gstreamer::Promise::with_change_func({
+            let webrtcbin = webrtcbin.clone();
+            move |reply| {
+                let offer = match reply {
+                    Ok(Some(reply)) => reply
+                        .get::<gstreamer_webrtc::WebRTCSessionDescription>("offer")
+                        .ok(),
+                    _ => None,
+                };
+                let Some(offer) = offer else {
+                    log::error!("webrtcbin failed to create an offer");
+                    return;
+                };
+                webrtcbin.emit_by_name::<()>(
+                    "set-local-description",
+                    &[&offer, &None::<gstreamer::Promise>],
+                );
+                match offer.sdp().as_text() {
+                    Ok(sdp) => {
+                        let _ = sender.send(json!({ "type": "offer", "sdp": sdp }));
+                    }
+                    Err(e) => log::error!("Failed to write the WebRTC offer: {e}"),
+                }
+            }
+        });
+        webrtcbin.emit_by_name::<()>("create-offer", &[&None::<gstreamer::Structure>, &promise]);
+        None
+    });
+
+    webrtcbin.connect("on-ice-candidate", false, move |values| {
+        let mline_index = values[1].get::<u32>().unwrap();
+        let candidate = values[2].get::<String>().unwrap();
+        let _ = sender.send(json!({
+            "type": "ice",
+            "candidate": candidate,
+            "sdpMLineIndex": mline_index,
+        }));
+        None
+    });
+}